@@ -12,14 +12,14 @@ LINUX_BIN="$TARGET_DIR/$LINUX_TARGET/release/bimble"
 WINDOWS_BIN="$TARGET_DIR/$WINDOWS_TARGET/release/bimble.exe"
 BIN_LINUX="$BIN_DIR/linux/bimble"
 BIN_WINDOWS="$BIN_DIR/windows/bimble.exe"
-LB_BJB="./lb.bjb"
-WB_BJB="./wb.bjb"
+LB_BJB="$TARGET_DIR/$LINUX_TARGET/release/bimble-vm"
+WB_BJB="$TARGET_DIR/$WINDOWS_TARGET/release/bimble-vm.exe"
 INSTALL_LINUX="$BIN_DIR/linux/install.sh"
 INSTALL_WINDOWS="$BIN_DIR/windows/install.bat"
 UNINSTALL_LINUX="$BIN_DIR/linux/uninstall.sh"
 UNINSTALL_WINDOWS="$BIN_DIR/windows/uninstall.bat"
 
-# Build the project for different targets
+# Build the project for different targets (the compiler and the bimble-vm runtime templates)
 cargo build --release --target $LINUX_TARGET
 cargo build --release --target $WINDOWS_TARGET
 
@@ -31,10 +31,10 @@ mkdir -p $BIN_DIR/windows
 # Copy binaries and other files
 cp $LINUX_BIN $BIN_LINUX
 cp $WINDOWS_BIN $BIN_WINDOWS
-cp $LB_BJB $BIN_DIR/linux
-cp $WB_BJB $BIN_DIR/linux
-cp $LB_BJB $BIN_DIR/windows
-cp $WB_BJB $BIN_DIR/windows
+cp $LB_BJB $BIN_DIR/linux/lb.bjb
+cp $WB_BJB $BIN_DIR/linux/wb.bjb
+cp $LB_BJB $BIN_DIR/windows/lb.bjb
+cp $WB_BJB $BIN_DIR/windows/wb.bjb
 
 # Generate install.sh for Linux
 cat << 'EOF' > $INSTALL_LINUX
@@ -0,0 +1,79 @@
+//! Embeds bimble in a Rust program: registers host functions, runs a script
+//! that calls them and checks what it printed.
+//!
+//!     cargo run --example embed
+
+use bimble::bytecode::Value;
+use bimble::vm::Captured;
+use bimble::Engine;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SCRIPT: &str = r#"
+ON report(){
+    log("report called")
+}
+may t = now()
+may who = "bimble"
+echoln("hello ", greet(who))
+report()
+echoln("started at ", t)
+"#;
+
+/// Registers the functions SCRIPT calls; `log` collects its arguments.
+fn register_hosts(
+    engine: &mut Engine,
+    started: u64,
+    log: Arc<Mutex<Vec<String>>>,
+) -> Result<(), String> {
+    engine.register("now", move |_| Ok(Value::Int(started as i128)))?;
+    engine.register("greet", |args| Ok(Value::Str(format!("{}!", args[0]))))?;
+    engine.register("log", move |args| {
+        log.lock().unwrap().push(args[0].to_string());
+        Ok(Value::Str(String::new()))
+    })
+}
+
+fn main() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut engine = Engine::new();
+    if let Err(e) = register_hosts(&mut engine, started, log.clone()) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+
+    // Collects the script's output so it can be checked
+    let out = Captured::default();
+    if let Err(e) = engine.run_with_output(SCRIPT, Box::new(out.clone())) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+    let printed = String::from_utf8_lossy(&out.take()).into_owned();
+    print!("{}", printed);
+    let expected = format!("hello bimble!\nstarted at {}\n", started);
+    if printed != expected || *log.lock().unwrap() != ["report called"] {
+        eprintln!("Unexpected output, wanted:\n{}", expected);
+        exit(1);
+    }
+
+    // Calls pass the undefined-function check only once registered
+    if let Err(e) = engine.run("now()") {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+    match Engine::new().run("now()") {
+        Err(e) if e.starts_with("Undefined function call 'now'") => {
+            println!("without hosts: {}", e)
+        }
+        other => {
+            eprintln!("Expected an undefined call, got {:?}", other);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use bimble::bytecode::Program;
+use bimble::exe::{sections, ExeFormat};
+use bimble::payload::{decode_payload, locate_payload, Located};
+use bimble::resources::{unpack, Resource};
+use crate::winres::read_resources;
+use colored::Colorize;
+use std::fs;
+
+/// Bytecode version and size of a payload's program.
+struct BytecodeInfo {
+    version: u16,
+    len: usize,
+}
+
+fn read_program(payload: &[u8]) -> Result<(Program, BytecodeInfo, Vec<Resource>), String> {
+    let (bytecode, archive) = decode_payload(payload)?;
+    let program = Program::from_bytes(bytecode)?;
+    let resources = if archive.is_empty() {
+        Vec::new()
+    } else {
+        unpack(archive)?
+    };
+    let info = BytecodeInfo {
+        // from_bytes has checked the header
+        version: u16::from_le_bytes([bytecode[4], bytecode[5]]),
+        len: bytecode.len(),
+    };
+    Ok((program, info, resources))
+}
+
+/// Checks the footer, payload bounds, checksum and payload header of a built
+/// artifact.
+pub fn verify_artifact(path: &str) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("Unable to read artifact {}: {}", path, e))?;
+    let located = locate_payload(&data)?;
+    let (program, bytecode, resources) = read_program(&located.payload)?;
+
+    println!("{}{}", "Artifact OK -> ".green(), path.bold().green());
+    print_payload(&located, &program, &bytecode, &resources);
+    Ok(())
+}
+
+/// Prints the executable layout of an artifact followed by its payload.
+pub fn inspect_artifact(path: &str) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("Unable to read artifact {}: {}", path, e))?;
+    let format = ExeFormat::detect(&data);
+
+    println!("{}{}", "Artifact -> ".blue(), path.bold().cyan());
+    println!(
+        "  executable      : {}, {} bytes",
+        format.name(),
+        data.len()
+    );
+    if format != ExeFormat::Unknown {
+        let sections = sections(&data)?;
+        println!("  sections        : {}", sections.len());
+        for section in sections.iter().filter(|s| !s.name.is_empty()) {
+            println!(
+                "    {:<16} offset {:<10} size {}",
+                section.name, section.offset, section.size
+            );
+        }
+    }
+
+    if let Some(info) = read_resources(&data)? {
+        println!("  file version    : {}", info.file_version);
+        for (key, value) in &info.strings {
+            println!("    {:<16} {}", key, value);
+        }
+        if !info.icons.is_empty() {
+            let sizes: Vec<String> = info
+                .icons
+                .iter()
+                .map(|(w, h)| format!("{}x{}", w, h))
+                .collect();
+            println!("  icon            : {}", sizes.join(", "));
+        }
+    }
+
+    let located = locate_payload(&data)?;
+    let (program, bytecode, resources) = read_program(&located.payload)?;
+    print_payload(&located, &program, &bytecode, &resources);
+    Ok(())
+}
+
+fn print_payload(
+    located: &Located,
+    program: &Program,
+    bytecode: &BytecodeInfo,
+    resources: &[Resource],
+) {
+    let footer = &located.footer;
+    match located.section {
+        Some(name) => println!("  embedded in     : section {}", name),
+        None => println!("  embedded in     : appended data"),
+    }
+    println!("  payload version : {}", footer.version);
+    println!("  payload offset  : {}", footer.offset);
+    println!("  payload length  : {}", footer.length);
+    println!("  compression     : {}", footer.codec.name());
+    println!("  obfuscated      : {}", footer.obfuscated);
+    println!(
+        "  bytecode        : v{}, {} bytes",
+        bytecode.version, bytecode.len
+    );
+    println!(
+        "  program         : {} instructions, {} functions, {} variables",
+        program.code.len(),
+        program.fns.len(),
+        program.vars.len()
+    );
+    match program.lines.len() {
+        0 => println!("  line table      : none"),
+        n => println!("  line table      : {} entries", n),
+    }
+    let limits = &program.limits;
+    let mut set = Vec::new();
+    if let Some(n) = limits.instructions {
+        set.push(format!("{} instructions", n));
+    }
+    if let Some(n) = limits.depth {
+        set.push(format!("depth {}", n));
+    }
+    if let Some(n) = limits.memory {
+        set.push(format!("{} bytes", n));
+    }
+    if let Some(time) = limits.time {
+        set.push(format!("{}ms", time.as_millis()));
+    }
+    if set.is_empty() {
+        println!("  limits          : none");
+    } else {
+        println!("  limits          : {}", set.join(", "));
+    }
+    println!("  capabilities    : {}", program.capabilities);
+    println!("  resources       : {}", resources.len());
+    for resource in resources {
+        println!("    {} ({} bytes)", resource.name, resource.data.len());
+    }
+    println!("  crc32           : {:08x}", footer.crc32);
+}
@@ -0,0 +1,46 @@
+//! Runtime template for built programs. `bimble` copies this executable and
+//! embeds the payload in it; at startup it reads the payload back from its
+//! own file and runs it.
+
+use bimble::bytecode::Program;
+use bimble::payload::{decode_payload, locate_payload, VM_MARKER};
+use bimble::resources::unpack;
+use bimble::vm::{RuntimeError, Vm};
+use colored::Colorize;
+use std::{env, fs, process::exit};
+
+fn main() {
+    // Keeps the marker in the binary for `PayloadFormat::read_by`
+    std::hint::black_box(VM_MARKER);
+    match run() {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            eprintln!("{}{}", "Error: ".bold().red(), e.to_string().bold().red());
+            exit(e.exit_code());
+        }
+        Err(e) => {
+            eprintln!("{}{}", "Error: ".bold().red(), e.bold().red());
+            exit(-1);
+        }
+    }
+}
+
+/// Loads the embedded program; the inner result is the program's own.
+fn run() -> Result<Result<(), RuntimeError>, String> {
+    let exe = env::current_exe().map_err(|e| format!("Unable to locate own executable: {}", e))?;
+    let data = fs::read(&exe)
+        .map_err(|e| format!("Unable to read own executable {}: {}", exe.display(), e))?;
+    let located = locate_payload(&data).map_err(|e| format!("No program payload: {}", e))?;
+    let (bytecode, archive) = decode_payload(&located.payload)?;
+    let mut program = Program::from_bytes(bytecode)?;
+    // Relative capability paths are relative to the artifact
+    if let Some(dir) = exe.parent() {
+        program.capabilities = program.capabilities.relative_to(dir);
+    }
+    let resources = if archive.is_empty() {
+        Vec::new()
+    } else {
+        unpack(archive)?
+    };
+    Ok(Vm::new(program, resources).run())
+}
@@ -0,0 +1,489 @@
+use crate::caps::{Capabilities, BUILTINS};
+use std::fmt;
+use std::time::Duration;
+
+/// Marker at the start of every serialized bytecode program.
+pub const BYTECODE_MAGIC: &[u8; 4] = b"BBBC";
+/// Bumped whenever the instruction set or serialization changes, so a VM can
+/// refuse programs it does not understand.
+pub const BYTECODE_VERSION: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i128),
+    Float(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// One VM instruction. Every instruction serializes to an opcode byte and a
+/// little-endian u32 operand (0 when unused).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// Push constant `n`.
+    Const(u32),
+    /// Push the value of variable `n`.
+    Load(u32),
+    /// Pop a value into variable `n`.
+    Store(u32),
+    /// Pop `n` values and print them, in push order, followed by a newline.
+    Echo(u32),
+    /// Call function `n`.
+    Call(u32),
+    /// Push the contents of the embedded resource named by constant `n`.
+    Resource(u32),
+    /// Pop the arguments of builtin `n` and call it, pushing its result if it
+    /// returns one.
+    Builtin(u32),
+    /// Pop the arguments of host call `n` and call the host function it
+    /// names, pushing its result if the call is used as a value.
+    Host(u32),
+    /// Return from the current function.
+    Ret,
+    /// Stop the program.
+    Halt,
+}
+
+impl Op {
+    fn encode(self) -> (u8, u32) {
+        match self {
+            Op::Const(n) => (0x01, n),
+            Op::Load(n) => (0x02, n),
+            Op::Store(n) => (0x03, n),
+            Op::Echo(n) => (0x04, n),
+            Op::Call(n) => (0x05, n),
+            Op::Ret => (0x06, 0),
+            Op::Halt => (0x07, 0),
+            Op::Resource(n) => (0x08, n),
+            Op::Builtin(n) => (0x09, n),
+            Op::Host(n) => (0x0a, n),
+        }
+    }
+
+    fn decode(opcode: u8, n: u32) -> Result<Self, String> {
+        Ok(match opcode {
+            0x01 => Op::Const(n),
+            0x02 => Op::Load(n),
+            0x03 => Op::Store(n),
+            0x04 => Op::Echo(n),
+            0x05 => Op::Call(n),
+            0x06 => Op::Ret,
+            0x07 => Op::Halt,
+            0x08 => Op::Resource(n),
+            0x09 => Op::Builtin(n),
+            0x0a => Op::Host(n),
+            _ => return Err(format!("Unknown opcode 0x{:02x}", opcode)),
+        })
+    }
+}
+
+/// Runtime limits the VM enforces on a program; `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Instructions executed.
+    pub instructions: Option<u64>,
+    /// Nested ON function calls.
+    pub depth: Option<u32>,
+    /// Bytes held by the stack, variables and call frames.
+    pub memory: Option<u64>,
+    /// Wall-clock run time.
+    pub time: Option<Duration>,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub name: String,
+    pub entry: u32,
+}
+
+/// A call to a function the embedding application registers with
+/// `Engine::register`, resolved by name when it runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostCall {
+    pub name: String,
+    pub args: u32,
+    /// Whether the result is kept on the stack.
+    pub returns: bool,
+}
+
+/// A lowered program. Execution starts at instruction 0; function bodies
+/// follow the `Halt` that ends the top-level code.
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    pub consts: Vec<Value>,
+    pub vars: Vec<String>,
+    pub fns: Vec<Function>,
+    pub code: Vec<Op>,
+    /// `(pc, line)` pairs marking the first instruction of each main.bb line.
+    pub lines: Vec<(u32, u32)>,
+    pub limits: Limits,
+    pub capabilities: Capabilities,
+    pub hosts: Vec<HostCall>,
+}
+
+impl Program {
+    /// Line of main.bb that instruction `pc` belongs to, if the program has
+    /// a line table.
+    pub fn line_of(&self, pc: usize) -> Option<u32> {
+        self.lines
+            .iter()
+            .take_while(|(start, _)| *start as usize <= pc)
+            .last()
+            .map(|(_, line)| *line)
+    }
+
+    /// Line of main.bb whose first instruction is `pc`, if any.
+    pub fn line_starting_at(&self, pc: usize) -> Option<u32> {
+        self.lines
+            .iter()
+            .rev()
+            .find(|(start, _)| *start as usize == pc)
+            .map(|(_, line)| *line)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(BYTECODE_MAGIC);
+        out.extend_from_slice(&BYTECODE_VERSION.to_le_bytes());
+
+        put_u32(&mut out, self.consts.len() as u32);
+        for value in &self.consts {
+            match value {
+                Value::Str(s) => {
+                    out.push(0);
+                    put_str(&mut out, s);
+                }
+                Value::Int(i) => {
+                    out.push(1);
+                    out.extend_from_slice(&i.to_le_bytes());
+                }
+                Value::Float(x) => {
+                    out.push(2);
+                    out.extend_from_slice(&x.to_bits().to_le_bytes());
+                }
+            }
+        }
+
+        put_u32(&mut out, self.vars.len() as u32);
+        for var in &self.vars {
+            put_str(&mut out, var);
+        }
+
+        put_u32(&mut out, self.fns.len() as u32);
+        for func in &self.fns {
+            put_str(&mut out, &func.name);
+            put_u32(&mut out, func.entry);
+        }
+
+        put_u32(&mut out, self.code.len() as u32);
+        for op in &self.code {
+            let (opcode, n) = op.encode();
+            out.push(opcode);
+            put_u32(&mut out, n);
+        }
+
+        put_u32(&mut out, self.lines.len() as u32);
+        for (pc, line) in &self.lines {
+            put_u32(&mut out, *pc);
+            put_u32(&mut out, *line);
+        }
+
+        // 0 stands for unlimited
+        let limits = &self.limits;
+        put_u64(&mut out, limits.instructions.unwrap_or(0));
+        put_u32(&mut out, limits.depth.unwrap_or(0));
+        put_u64(&mut out, limits.memory.unwrap_or(0));
+        put_u64(&mut out, limits.time.map_or(0, |t| t.as_millis() as u64));
+
+        let capabilities = &self.capabilities;
+        for paths in [&capabilities.read, &capabilities.write] {
+            put_u32(&mut out, paths.len() as u32);
+            for path in paths {
+                put_str(&mut out, path);
+            }
+        }
+        out.push(
+            capabilities.process as u8
+                | (capabilities.env as u8) << 1
+                | (capabilities.network as u8) << 2,
+        );
+
+        put_u32(&mut out, self.hosts.len() as u32);
+        for host in &self.hosts {
+            put_str(&mut out, &host.name);
+            put_u32(&mut out, host.args);
+            out.push(host.returns as u8);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader { data, pos: 0 };
+        if r.take(4)? != BYTECODE_MAGIC {
+            return Err("Program does not start with the BBBC header".to_string());
+        }
+        let version = u16::from_le_bytes(r.take(2)?.try_into().unwrap());
+        if version != BYTECODE_VERSION {
+            return Err(format!(
+                "Unsupported bytecode version {} (expected {})",
+                version, BYTECODE_VERSION
+            ));
+        }
+
+        let mut program = Program::default();
+        for _ in 0..r.u32()? {
+            let value = match r.take(1)?[0] {
+                0 => Value::Str(r.str()?),
+                1 => Value::Int(i128::from_le_bytes(r.take(16)?.try_into().unwrap())),
+                2 => Value::Float(f64::from_bits(u64::from_le_bytes(
+                    r.take(8)?.try_into().unwrap(),
+                ))),
+                tag => return Err(format!("Unknown constant tag {}", tag)),
+            };
+            program.consts.push(value);
+        }
+        for _ in 0..r.u32()? {
+            program.vars.push(r.str()?);
+        }
+        for _ in 0..r.u32()? {
+            let name = r.str()?;
+            let entry = r.u32()?;
+            program.fns.push(Function { name, entry });
+        }
+        for _ in 0..r.u32()? {
+            let opcode = r.take(1)?[0];
+            let n = r.u32()?;
+            program.code.push(Op::decode(opcode, n)?);
+        }
+        for _ in 0..r.u32()? {
+            let pc = r.u32()?;
+            let line = r.u32()?;
+            program.lines.push((pc, line));
+        }
+        let limit = |n: u64| Some(n).filter(|n| *n > 0);
+        program.limits = Limits {
+            instructions: limit(r.u64()?),
+            depth: limit(r.u32()? as u64).map(|n| n as u32),
+            memory: limit(r.u64()?),
+            time: limit(r.u64()?).map(Duration::from_millis),
+        };
+        let capabilities = &mut program.capabilities;
+        for _ in 0..r.u32()? {
+            capabilities.read.push(r.str()?);
+        }
+        for _ in 0..r.u32()? {
+            capabilities.write.push(r.str()?);
+        }
+        let flags = r.take(1)?[0];
+        capabilities.process = flags & 1 != 0;
+        capabilities.env = flags & 2 != 0;
+        capabilities.network = flags & 4 != 0;
+        for _ in 0..r.u32()? {
+            let name = r.str()?;
+            let args = r.u32()?;
+            let returns = r.take(1)?[0] != 0;
+            program.hosts.push(HostCall {
+                name,
+                args,
+                returns,
+            });
+        }
+
+        if r.pos != data.len() {
+            return Err(format!(
+                "{} trailing bytes after program",
+                data.len() - r.pos
+            ));
+        }
+        program.validate()?;
+        Ok(program)
+    }
+
+    /// Checks that every operand refers to an existing constant, variable,
+    /// function or instruction.
+    fn validate(&self) -> Result<(), String> {
+        let code_len = self.code.len() as u32;
+        for func in &self.fns {
+            if func.entry >= code_len {
+                return Err(format!("Function '{}' starts outside the code", func.name));
+            }
+        }
+        for (pc, op) in self.code.iter().enumerate() {
+            let bad = match *op {
+                Op::Const(n) | Op::Resource(n) => n as usize >= self.consts.len(),
+                Op::Load(n) | Op::Store(n) => n as usize >= self.vars.len(),
+                Op::Call(n) => n as usize >= self.fns.len(),
+                Op::Builtin(n) => n as usize >= BUILTINS.len(),
+                Op::Host(n) => n as usize >= self.hosts.len(),
+                Op::Echo(_) | Op::Ret | Op::Halt => false,
+            };
+            if bad {
+                return Err(format!("Invalid operand in {:?} at pc {}", op, pc));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err("Unexpected end of program".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| "Invalid UTF-8 in program string".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A program using every section of the format.
+    fn sample() -> Program {
+        Program {
+            consts: vec![
+                Value::Str("hi".to_string()),
+                Value::Int(-7),
+                Value::Float(2.5),
+            ],
+            vars: vec!["x".to_string()],
+            fns: vec![Function {
+                name: "f".to_string(),
+                entry: 8,
+            }],
+            code: vec![
+                Op::Const(0),
+                Op::Store(0),
+                Op::Call(0),
+                Op::Resource(0),
+                Op::Builtin(0),
+                Op::Host(0),
+                Op::Echo(2),
+                Op::Halt,
+                Op::Load(0),
+                Op::Ret,
+            ],
+            lines: vec![(0, 1), (2, 2), (8, 4)],
+            limits: Limits {
+                instructions: Some(100),
+                depth: Some(3),
+                memory: Some(1 << 20),
+                time: Some(Duration::from_millis(250)),
+            },
+            capabilities: Capabilities {
+                read: vec!["data".to_string()],
+                write: vec!["*".to_string()],
+                process: true,
+                env: false,
+                network: true,
+            },
+            hosts: vec![HostCall {
+                name: "now".to_string(),
+                args: 0,
+                returns: true,
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = sample();
+        let back = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(format!("{:?}", back), format!("{:?}", program));
+    }
+
+    #[test]
+    fn malformed_programs_are_rejected() {
+        let bytes = sample().to_bytes();
+        let error = |data: &[u8]| Program::from_bytes(data).err().unwrap();
+
+        assert!(error(&bytes[1..]).contains("BBBC header"));
+        let mut future = bytes.clone();
+        future[4..6].copy_from_slice(&(BYTECODE_VERSION + 1).to_le_bytes());
+        assert!(error(&future).starts_with("Unsupported bytecode version"));
+        let mut older = bytes.clone();
+        older[4..6].copy_from_slice(&0u16.to_le_bytes());
+        assert!(error(&older).starts_with("Unsupported bytecode version"));
+        assert_eq!(
+            error(&bytes[..bytes.len() - 1]),
+            "Unexpected end of program"
+        );
+        let trailing = [bytes.as_slice(), &[0]].concat();
+        assert_eq!(error(&trailing), "1 trailing bytes after program");
+    }
+
+    #[test]
+    fn operands_are_validated() {
+        let invalid = |change: fn(&mut Program)| {
+            let mut program = sample();
+            change(&mut program);
+            Program::from_bytes(&program.to_bytes()).err().unwrap()
+        };
+        assert_eq!(
+            invalid(|p| p.code[8] = Op::Load(1)),
+            "Invalid operand in Load(1) at pc 8"
+        );
+        assert_eq!(
+            invalid(|p| p.code[4] = Op::Builtin(BUILTINS.len() as u32)),
+            format!("Invalid operand in Builtin({}) at pc 4", BUILTINS.len())
+        );
+        assert_eq!(
+            invalid(|p| p.hosts.clear()),
+            "Invalid operand in Host(0) at pc 5"
+        );
+        assert_eq!(
+            invalid(|p| p.fns[0].entry = 10),
+            "Function 'f' starts outside the code"
+        );
+    }
+}
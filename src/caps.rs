@@ -0,0 +1,324 @@
+use crate::bytecode::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fmt};
+
+/// Something outside the program a builtin can touch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    Read,
+    Write,
+    Process,
+    Env,
+    /// Declarable so artifacts can state it; no builtin needs it yet.
+    Network,
+}
+
+impl Capability {
+    /// Key of the capability in the `[capabilities]` section of cfg.bcf.
+    pub fn key(self) -> &'static str {
+        match self {
+            Capability::Read => "Read",
+            Capability::Write => "Write",
+            Capability::Process => "Process",
+            Capability::Env => "Env",
+            Capability::Network => "Network",
+        }
+    }
+}
+
+/// Capabilities granted to a program. `read` and `write` list the files and
+/// directories the program may access; `*` allows every path. Relative
+/// entries are kept as written in cfg.bcf and resolved with `relative_to`
+/// where the program runs: against the project folder for `bimble run`,
+/// `debug` and `dap`, and against the artifact's directory for a built
+/// program.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    pub read: Vec<String>,
+    pub write: Vec<String>,
+    pub process: bool,
+    pub env: bool,
+    pub network: bool,
+}
+
+impl Capabilities {
+    /// Everything, for code typed at the repl.
+    pub fn all() -> Self {
+        Self {
+            read: vec!["*".to_string()],
+            write: vec!["*".to_string()],
+            process: true,
+            env: true,
+            network: true,
+        }
+    }
+
+    /// Joins relative `read` and `write` entries onto `dir`.
+    pub fn relative_to(&self, dir: &Path) -> Self {
+        let resolve = |roots: &[String]| {
+            roots
+                .iter()
+                .map(|root| match root.as_str() {
+                    "*" => root.clone(),
+                    _ if Path::new(root).is_absolute() => root.clone(),
+                    _ => dir.join(root).display().to_string(),
+                })
+                .collect()
+        };
+        Self {
+            read: resolve(&self.read),
+            write: resolve(&self.write),
+            ..self.clone()
+        }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Read => !self.read.is_empty(),
+            Capability::Write => !self.write.is_empty(),
+            Capability::Process => self.process,
+            Capability::Env => self.env,
+            Capability::Network => self.network,
+        }
+    }
+
+    /// Errors unless `capability` is granted at all.
+    pub fn require(&self, capability: Capability) -> Result<(), String> {
+        if self.has(capability) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Capability '{}' is not declared in [capabilities]",
+                capability.key()
+            ))
+        }
+    }
+
+    fn require_path(&self, capability: Capability, path: &str) -> Result<(), String> {
+        self.require(capability)?;
+        let roots = match capability {
+            Capability::Write => &self.write,
+            _ => &self.read,
+        };
+        if within(path, roots) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Path '{}' is outside the '{}' capability",
+                path,
+                capability.key()
+            ))
+        }
+    }
+}
+
+/// Lists the granted capabilities, e.g. `Read data, Env`.
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut granted = Vec::new();
+        if !self.read.is_empty() {
+            granted.push(format!("Read {}", self.read.join(" ")));
+        }
+        if !self.write.is_empty() {
+            granted.push(format!("Write {}", self.write.join(" ")));
+        }
+        for (flag, capability) in [
+            (self.process, Capability::Process),
+            (self.env, Capability::Env),
+            (self.network, Capability::Network),
+        ] {
+            if flag {
+                granted.push(capability.key().to_string());
+            }
+        }
+        if granted.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", granted.join(", "))
+        }
+    }
+}
+
+/// Whether `path` is one of `roots` or inside one of them, after resolving
+/// `..` and symlinks. A file that does not exist yet is resolved through its
+/// parent directory.
+fn within(path: &str, roots: &[String]) -> bool {
+    if roots.iter().any(|root| root == "*") {
+        return true;
+    }
+    let Some(path) = resolve(Path::new(path)) else {
+        return false;
+    };
+    roots
+        .iter()
+        .filter_map(|root| resolve(Path::new(root)))
+        .any(|root| path.starts_with(root))
+}
+
+fn resolve(path: &Path) -> Option<PathBuf> {
+    fs::canonicalize(path).ok().or_else(|| {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        Some(fs::canonicalize(parent).ok()?.join(path.file_name()?))
+    })
+}
+
+/// A function provided by the runtime rather than defined with `ON`.
+pub struct Builtin {
+    pub name: &'static str,
+    pub args: usize,
+    /// Whether a call produces a value, usable in `may` and `echoln`, rather
+    /// than standing alone as a statement.
+    pub returns: bool,
+    pub capability: Capability,
+}
+
+/// Every builtin, numbered by position for `Op::Builtin`.
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "read",
+        args: 1,
+        returns: true,
+        capability: Capability::Read,
+    },
+    Builtin {
+        name: "env",
+        args: 1,
+        returns: true,
+        capability: Capability::Env,
+    },
+    Builtin {
+        name: "write",
+        args: 2,
+        returns: false,
+        capability: Capability::Write,
+    },
+    Builtin {
+        name: "run",
+        args: 1,
+        returns: false,
+        capability: Capability::Process,
+    },
+];
+
+pub fn builtin(name: &str) -> Option<usize> {
+    BUILTINS.iter().position(|b| b.name == name)
+}
+
+/// Calls builtin `id` with its arguments, in order, after checking them
+/// against `capabilities`.
+pub fn call(
+    id: usize,
+    args: &[Value],
+    capabilities: &Capabilities,
+) -> Result<Option<Value>, String> {
+    let builtin = &BUILTINS[id];
+    capabilities.require(builtin.capability)?;
+    let arg = |i: usize| args[i].to_string();
+    match builtin.name {
+        "read" => {
+            let path = arg(0);
+            capabilities.require_path(Capability::Read, &path)?;
+            let text =
+                fs::read_to_string(&path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            Ok(Some(Value::Str(text)))
+        }
+        "env" => Ok(Some(Value::Str(env::var(arg(0)).unwrap_or_default()))),
+        "write" => {
+            let path = arg(0);
+            capabilities.require_path(Capability::Write, &path)?;
+            fs::write(&path, arg(1)).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+            Ok(None)
+        }
+        "run" => {
+            let command = arg(0);
+            let mut words = command.split_whitespace();
+            let program = words
+                .next()
+                .ok_or_else(|| "Empty command passed to 'run'".to_string())?;
+            let status = Command::new(program)
+                .args(words)
+                .status()
+                .map_err(|e| format!("Unable to run '{}': {}", command, e))?;
+            if !status.success() {
+                return Err(format!("Command '{}' failed with {}", command, status));
+            }
+            Ok(None)
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<Value> {
+        values.iter().map(|v| Value::Str(v.to_string())).collect()
+    }
+
+    #[test]
+    fn builtins_need_their_capability() {
+        let none = Capabilities::default();
+        for (id, b) in BUILTINS.iter().enumerate() {
+            let values = args(&vec!["x"; b.args]);
+            let err = call(id, &values, &none).err().unwrap();
+            let expected = format!(
+                "Capability '{}' is not declared in [capabilities]",
+                b.capability.key()
+            );
+            assert_eq!(err, expected, "{}", b.name);
+        }
+    }
+
+    #[test]
+    fn paths_stay_inside_their_roots() {
+        let dir = env::temp_dir().join(format!("bimble-caps-{}", std::process::id()));
+        let data = dir.join("data");
+        fs::create_dir_all(&data).unwrap();
+        let caps = Capabilities {
+            read: vec![data.display().to_string()],
+            write: vec![data.display().to_string()],
+            ..Capabilities::default()
+        };
+        let write = builtin("write").unwrap();
+        let read = builtin("read").unwrap();
+
+        let inside = data.join("note.txt").display().to_string();
+        assert!(call(write, &args(&[&inside, "hi"]), &caps).is_ok());
+        let text = call(read, &args(&[&inside]), &caps).ok().unwrap();
+        assert_eq!(text.unwrap().to_string(), "hi");
+
+        let escaped = data.join("../note.txt").display().to_string();
+        let err = call(write, &args(&[&escaped, "hi"]), &caps).err().unwrap();
+        assert!(err.ends_with("is outside the 'Write' capability"));
+        let err = call(read, &args(&[&escaped]), &caps).err().unwrap();
+        assert!(err.ends_with("is outside the 'Read' capability"));
+
+        let everything = Capabilities::all();
+        let outside = dir.join("other.txt").display().to_string();
+        assert!(call(write, &args(&[&outside, "x"]), &everything).is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relative_roots_join_the_directory() {
+        let absolute = env::temp_dir().display().to_string();
+        let caps = Capabilities {
+            read: vec!["data".to_string(), "*".to_string()],
+            write: vec![absolute.clone()],
+            env: true,
+            ..Capabilities::default()
+        };
+        let resolved = caps.relative_to(Path::new("/srv/app"));
+        let data = Path::new("/srv/app").join("data").display().to_string();
+        assert_eq!(resolved.read, [data, "*".to_string()]);
+        assert_eq!(resolved.write, [absolute]);
+        assert!(resolved.env);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::caps::{builtin, Capabilities, BUILTINS};
 use colored::Colorize;
 use std::process::exit;
 
@@ -15,63 +16,172 @@ pub enum VT {
     Int,
     Float,
     RT,
+    /// The result of a host function call, typed by the host at runtime.
+    Host,
 }
 
-pub fn check(code: Vec<String>) {
-    let mut vrs: Vec<Var> = Vec::new();
-    let mut fns: Vec<String> = Vec::new();
-    let mut called_fns: Vec<String> = Vec::new();
-    let mut index = 1;
+/// A statement that survived `check`, ready to be lowered by the code generator.
+#[derive(Clone, Debug)]
+pub enum Stmt {
+    Let { name: String, val: String, vt: VT },
+    Echo(Vec<String>),
+    Fn { name: String, has_body: bool },
+    End,
+    Call(String),
+    /// A call to builtin `id` used as a statement.
+    Builtin { id: usize, args: Vec<String> },
+    /// A call to a host function registered with `Engine::register`.
+    Host { name: String, args: Vec<String> },
+}
 
-    for line in code.clone() {
-        let cd = line.trim();
+/// A checked statement with its line number in main.bb (0 for defines).
+#[derive(Clone, Debug)]
+pub struct CheckedLine {
+    pub index: usize,
+    pub stmt: Stmt,
+}
+
+/// Checker state carried from one line to the next: declared variables,
+/// defined functions and calls to functions that were not defined yet.
+#[derive(Clone)]
+pub struct Checker {
+    vrs: Vec<Var>,
+    fns: Vec<String>,
+    called_fns: Vec<String>,
+    resources: Vec<String>,
+    /// What builtins may touch; calls needing anything else are rejected.
+    capabilities: Capabilities,
+    /// Names of the host functions the runtime will provide.
+    hosts: Vec<String>,
+}
+
+impl Checker {
+    pub fn new(resources: &[String], capabilities: &Capabilities) -> Self {
+        Self {
+            vrs: Vec::new(),
+            fns: Vec::new(),
+            called_fns: Vec::new(),
+            resources: resources.to_vec(),
+            capabilities: capabilities.clone(),
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Makes calls to host function `name` valid.
+    pub fn host(&mut self, name: &str) {
+        self.hosts.push(name.to_string());
+    }
+
+    /// Declares a compile-time define as if it were `may key = value`.
+    pub fn define(&mut self, key: &str, value: &str) -> Result<Option<Stmt>, String> {
+        let decl = format!("may {} = {}", key, value);
+        let context = Context {
+            resources: &self.resources,
+            capabilities: &self.capabilities,
+            hosts: &self.hosts,
+        };
+        pvr(&decl, &mut self.vrs, 0, &context)
+    }
 
+    /// Checks one line of source; `index` is its line number.
+    pub fn line(&mut self, index: usize, line: &str) -> Result<Option<Stmt>, String> {
+        let cd = line.trim();
         if cd.ends_with(';') {
-            eprintln!(
-                "{}{}{}{}{}",
-                "Error: ".red(),
-                "Unexpected semicolon".bold().red(),
-                " at code (".red(),
-                index.to_string().bold().red(),
-                format!("): {}", cd).red().bold()
-            );
+            return Err("Unexpected semicolon".to_string());
+        }
+        let context = Context {
+            resources: &self.resources,
+            capabilities: &self.capabilities,
+            hosts: &self.hosts,
+        };
+        parse_line(
+            cd,
+            &mut self.vrs,
+            index,
+            &mut self.fns,
+            &mut self.called_fns,
+            &context,
+        )
+    }
+
+    /// Calls seen so far to functions that are still undefined. Calls to
+    /// functions defined later count as resolved.
+    pub fn undefined_calls(&self) -> Vec<String> {
+        self.called_fns
+            .iter()
+            .map(|call| call.trim_end_matches("()").to_string())
+            .filter(|call| !self.fns.contains(call))
+            .collect()
+    }
+
+    /// Forgets pending calls, e.g. once they have been executed or reported.
+    pub fn clear_calls(&mut self) {
+        self.called_fns.clear();
+    }
+}
+
+/// Like `check_program` with a fresh `Checker`, but reports the error and
+/// exits.
+pub fn check(
+    code: Vec<String>,
+    defines: &[(String, String)],
+    resources: &[String],
+    capabilities: &Capabilities,
+) -> Vec<CheckedLine> {
+    let mut checker = Checker::new(resources, capabilities);
+    match check_program(&mut checker, &code, defines) {
+        Ok(checked) => checked,
+        Err(e) => {
+            eprintln!("{}{}", "Error: ".red(), e.bold().red());
             exit(-1);
         }
+    }
+}
 
-        match parse_line(cd, &mut vrs, index, &mut fns, &mut called_fns) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!(
-                    "{}{}{}{}{}",
-                    "Error: ".red(),
-                    e.red(),
-                    " at code (".red(),
-                    index.to_string().bold().red(),
-                    format!("): {}", cd).red().bold()
-                );
-                exit(-1);
-            }
+/// Checks every line of `code` after predeclaring the compile-time `defines`,
+/// then that every called function is defined. Line errors read
+/// `msg at code (N): line`.
+pub fn check_program(
+    checker: &mut Checker,
+    code: &[String],
+    defines: &[(String, String)],
+) -> Result<Vec<CheckedLine>, String> {
+    let mut checked = Vec::new();
+
+    // Compile-time defines are predeclared before the first line of main.bb
+    for (key, value) in defines {
+        match checker.define(key, value) {
+            Ok(Some(stmt)) => checked.push(CheckedLine { index: 0, stmt }),
+            Ok(None) => (),
+            Err(e) => return Err(format!("{} in define {}", e, key)),
         }
-        index += 1;
     }
+    for (index, line) in code.iter().enumerate() {
+        let index = index + 1;
 
-    // Final pass: Check for undefined function calls
-    for call in called_fns {
-        let call = call.trim_end_matches("()");
-        if !fns.contains(&call.to_string()) {
-            //println!("called func : {} | fns : {:?}",&call,&fns);
-            eprintln!(
-                "{}{}{}{}",
-                "Error: ".red(),
-                format!("Undefined function call '{}'", call).bold().red(),
-                " found.".red(),
-                format!(" Consider adding a function definition for '{}'", call)
-                    .bold()
-                    .red()
-            );
-            exit(-1);
+        match checker.line(index, line) {
+            Ok(Some(stmt)) => checked.push(CheckedLine { index, stmt }),
+            Ok(None) => (),
+            Err(e) => return Err(format!("{} at code ({}): {}", e, index, line.trim())),
         }
     }
+
+    // Final pass: Check for undefined function calls
+    if let Some(call) = checker.undefined_calls().first() {
+        return Err(format!(
+            "Undefined function call '{}' found. Consider adding a function definition for '{}'",
+            call, call
+        ));
+    }
+
+    Ok(checked)
+}
+
+/// What the program may refer to besides its own variables and functions.
+struct Context<'a> {
+    resources: &'a [String],
+    capabilities: &'a Capabilities,
+    hosts: &'a [String],
 }
 
 fn parse_line(
@@ -80,21 +190,55 @@ fn parse_line(
     index: usize,
     fns: &mut Vec<String>,
     called_fns: &mut Vec<String>,
-) -> Result<(), String> {
+    context: &Context,
+) -> Result<Option<Stmt>, String> {
     if line.starts_with("echoln") && line.ends_with(")") {
-        peln(line, vrs, index)
+        peln(line, vrs, index, context)?;
+        Ok(Some(Stmt::Echo(parse_arguments(&line[7..line.len() - 1]))))
     } else if line.starts_with("may ") {
-        pvr(line, vrs, index)
-    } else if line.starts_with("#") {
-        Ok(()) // Comment line
-    } else if line.trim().replace(" ", "").is_empty() {
-        Ok(())
-    } else if line.trim().starts_with("ON ") && line.trim().ends_with("{}") {
-        pef(line, vrs, index, fns)
-    } else if line.trim().starts_with("ON ") && line.trim().ends_with("{") {
-        pef(line, vrs, index, fns)
+        pvr(line, vrs, index, context)
+    } else if line.starts_with("#") || line.trim().replace(" ", "").is_empty() {
+        Ok(None) // Comment or blank line
+    } else if line.trim().starts_with("ON ")
+        && (line.trim().ends_with("{}") || line.trim().ends_with("{"))
+    {
+        pef(line, vrs, index, fns)?;
+        let name = fns.last().cloned().unwrap_or_default();
+        if let Some(id) = builtin(&name).filter(|id| !shadowable(*id, context.capabilities)) {
+            fns.pop();
+            return Err(format!(
+                "'{}' is a builtin function while the '{}' capability is declared; rename the function or drop the capability",
+                name,
+                BUILTINS[id].capability.key()
+            ));
+        }
+        if context.hosts.contains(&name) {
+            fns.pop();
+            return Err(format!("'{}' is a host function", name));
+        }
+        Ok(Some(Stmt::Fn {
+            name: fns.last().cloned().unwrap_or_default(),
+            has_body: !line.trim().ends_with("{}"),
+        }))
     } else if line.trim() == "}" {
-        Ok(())
+        Ok(Some(Stmt::End))
+    } else if let Some((id, args)) = builtin_call(line.trim())
+        .filter(|(id, args)| !args.is_empty() || !shadowable(*id, context.capabilities))
+    {
+        if BUILTINS[id].returns {
+            return Err(format!(
+                "'{}' returns a value, assign it with may",
+                BUILTINS[id].name
+            ));
+        }
+        check_builtin(id, &args, vrs, context.capabilities)?;
+        Ok(Some(Stmt::Builtin { id, args }))
+    } else if let Some((name, args)) = host_call(line.trim(), context.hosts) {
+        check_arguments(name, &args, vrs)?;
+        Ok(Some(Stmt::Host {
+            name: name.to_string(),
+            args,
+        }))
     } else {
         let mut iserr = true;
         for i in fns {
@@ -106,10 +250,10 @@ fn parse_line(
         }
         if iserr {
             called_fns.push(line.trim().to_string());
-            Ok(())
-        } else {
-            Ok(())
         }
+        Ok(Some(Stmt::Call(
+            line.trim().trim_end_matches("()").to_string(),
+        )))
     }
 }
 
@@ -130,38 +274,17 @@ fn pef(line: &str, vrs: &[Var], index: usize, fns: &mut Vec<String>) -> Result<(
             if i == '(' {
                 if gsb {
                     // Check if '(' already encountered
-                    eprintln!(
-                        "{}{}{}{}",
-                        "ERR - Unexpected '(' after '(': code(".red(),
-                        index.to_string().red(),
-                        ") -> ".red(),
-                        line.bold().red()
-                    );
-                    exit(0);
+                    return Err("Unexpected '(' after '('".to_string());
                 }
                 gsb = true;
                 curwrd = curwrd.trim().to_string();
                 if curwrd.contains(" ") {
-                    eprintln!(
-                        "{}{}{}{}",
-                        "ERR - Function Names Can't Have Whitespaces: code(".red(),
-                        index.to_string().red(),
-                        ") -> ".red(),
-                        line.bold().red()
-                    );
-                    exit(0);
+                    return Err("Function Names Can't Have Whitespaces".to_string());
                 }
                 fns.push(curwrd.clone());
                 curwrd.clear();
             } else if i == '{' {
-                eprintln!(
-                    "{}{}{}{}",
-                    "ERR - Unexpected '{' before function signature complete: code(".red(),
-                    index.to_string().red(),
-                    ") -> ".red(),
-                    line.bold().red()
-                );
-                exit(0);
+                return Err("Unexpected '{' before function signature complete".to_string());
             } else {
                 curwrd.push(i);
             }
@@ -169,23 +292,9 @@ fn pef(line: &str, vrs: &[Var], index: usize, fns: &mut Vec<String>) -> Result<(
             if i == ')' {
                 gmd = true;
             } else if i == '(' {
-                eprintln!(
-                    "{}{}{}{}",
-                    "ERR - Unexpected '(' after '(': code(".red(),
-                    index.to_string().red(),
-                    ") -> ".red(),
-                    line.bold().red()
-                );
-                exit(0);
+                return Err("Unexpected '(' after '('".to_string());
             } else if i == '{' {
-                eprintln!(
-                    "{}{}{}{}",
-                    "ERR - Unexpected '{' before closing ')': code(".red(),
-                    index.to_string().red(),
-                    ") -> ".red(),
-                    line.bold().red()
-                );
-                exit(0);
+                return Err("Unexpected '{' before closing ')'".to_string());
             } else {
                 curwrd.push(i);
             }
@@ -193,57 +302,34 @@ fn pef(line: &str, vrs: &[Var], index: usize, fns: &mut Vec<String>) -> Result<(
             if i == '{' {
                 break;
             } else if i == ')' {
-                eprintln!(
-                    "{}{}{}{}",
-                    "ERR - Unexpected ')' after closing ')': code(".red(),
-                    index.to_string().red(),
-                    ") -> ".red(),
-                    line.bold().red()
-                );
-                exit(0);
+                return Err("Unexpected ')' after closing ')'".to_string());
             } else if i == '(' {
-                eprintln!(
-                    "{}{}{}{}",
-                    "ERR - Unexpected '(' after closing ')': code(".red(),
-                    index.to_string().red(),
-                    ") -> ".red(),
-                    line.bold().red()
-                );
-                exit(0);
+                return Err("Unexpected '(' after closing ')'".to_string());
             } else if i != ' ' {
-                eprintln!(
-                    "{}{}{}{}",
-                    "ERR - Unexpected characters after function signature: code(".red(),
-                    index.to_string().red(),
-                    ") -> ".red(),
-                    line.bold().red()
-                );
-                exit(0);
+                return Err("Unexpected characters after function signature".to_string());
             }
         }
     }
 
     if !gsb || !gmd {
-        eprintln!(
-            "{}{}{}{}",
-            "ERR - Function signature incomplete: code(".red(),
-            index.to_string().red(),
-            ") -> ".red(),
-            line.bold().red()
-        );
-        exit(0);
+        return Err("Function signature incomplete".to_string());
     }
     Ok(())
 }
 
 #[allow(dead_code, unused_assignments, unused_variables)]
-
-fn peln(line: &str, vrs: &Vec<Var>, index: usize) -> Result<(), String> {
+fn peln(line: &str, vrs: &[Var], index: usize, context: &Context) -> Result<(), String> {
     let tcts = &line[7..line.len() - 1];
     let args = parse_arguments(tcts);
 
     for arg in &args {
-        if !is_literal(arg) && !variable_exists(arg, vrs) {
+        if let Some(name) = resource_name(arg) {
+            check_resource(name, context.resources)?;
+        } else if let Some((id, args)) = builtin_call(arg) {
+            check_value_builtin(id, &args, vrs, context.capabilities)?;
+        } else if let Some((name, args)) = host_call(arg, context.hosts) {
+            check_arguments(name, &args, vrs)?;
+        } else if !is_literal(arg) && !variable_exists(arg, vrs) {
             return Err(format!("Invalid argument to 'echoln' -> {}", arg));
         }
     }
@@ -285,16 +371,132 @@ fn parse_arguments(tcts: &str) -> Vec<String> {
     args
 }
 
+/// Returns the file name of a `resource("name")` call.
+pub fn resource_name(arg: &str) -> Option<&str> {
+    let inner = arg.strip_prefix("resource(")?.strip_suffix(')')?.trim();
+    if inner.len() >= 2 && is_literal(inner) {
+        Some(&inner[1..inner.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Splits a call, `name(args)`, into the function name and its arguments.
+pub fn call_parts(text: &str) -> Option<(&str, Vec<String>)> {
+    let (name, rest) = text.split_once('(')?;
+    let name = name.trim();
+    let inner = rest.strip_suffix(')')?;
+    is_identifier(name).then(|| (name, parse_arguments(inner)))
+}
+
+/// Whether `name` can name a function: a letter or `_`, then letters, digits
+/// and `_`.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits a call to a builtin into the builtin's number and its arguments.
+pub fn builtin_call(text: &str) -> Option<(usize, Vec<String>)> {
+    let (name, args) = call_parts(text)?;
+    Some((builtin(name)?, args))
+}
+
+/// Whether an ON function may take the name of builtin `id`. Builtins are
+/// only reserved once their capability is declared, so programs that defined
+/// e.g. `ON run(){}` before builtins existed keep working; `run()` without
+/// arguments then calls the ON function.
+fn shadowable(id: usize, capabilities: &Capabilities) -> bool {
+    !capabilities.has(BUILTINS[id].capability)
+}
+
+/// Splits a call to one of `hosts` into its name and arguments.
+fn host_call<'a>(text: &'a str, hosts: &[String]) -> Option<(&'a str, Vec<String>)> {
+    call_parts(text).filter(|(name, _)| hosts.iter().any(|h| h == name))
+}
+
+/// Arguments of builtin and host calls are string literals or declared
+/// variables.
+fn check_arguments(name: &str, args: &[String], vrs: &[Var]) -> Result<(), String> {
+    match args
+        .iter()
+        .find(|arg| !is_literal(arg) && !variable_exists(arg, vrs))
+    {
+        Some(arg) => Err(format!("Invalid argument to '{}' -> {}", name, arg)),
+        None => Ok(()),
+    }
+}
+
+/// Checks the arguments of a builtin call and that its capability is
+/// declared.
+fn check_builtin(
+    id: usize,
+    args: &[String],
+    vrs: &[Var],
+    capabilities: &Capabilities,
+) -> Result<(), String> {
+    let builtin = &BUILTINS[id];
+    if args.len() != builtin.args {
+        return Err(format!(
+            "'{}' takes {} argument(s), found {}",
+            builtin.name,
+            builtin.args,
+            args.len()
+        ));
+    }
+    check_arguments(builtin.name, args, vrs)?;
+    if !capabilities.has(builtin.capability) {
+        return Err(format!(
+            "'{}' needs the '{}' capability, which is not declared in [capabilities]",
+            builtin.name,
+            builtin.capability.key()
+        ));
+    }
+    Ok(())
+}
+
+fn check_value_builtin(
+    id: usize,
+    args: &[String],
+    vrs: &[Var],
+    capabilities: &Capabilities,
+) -> Result<(), String> {
+    if !BUILTINS[id].returns {
+        return Err(format!("'{}' does not return a value", BUILTINS[id].name));
+    }
+    check_builtin(id, args, vrs, capabilities)
+}
+
+fn check_resource(name: &str, resources: &[String]) -> Result<(), String> {
+    if resources.iter().any(|r| r == name) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Resource '{}' is not listed in the 'Resources' config key",
+            name
+        ))
+    }
+}
+
 fn is_literal(arg: &str) -> bool {
     (arg.starts_with('"') && arg.ends_with('"')) || (arg.starts_with('\'') && arg.ends_with('\''))
 }
 
-fn variable_exists(arg: &str, vrs: &Vec<Var>) -> bool {
+fn variable_exists(arg: &str, vrs: &[Var]) -> bool {
     vrs.iter().any(|var| var.name == *arg)
 }
 
 #[allow(dead_code, unused_assignments, unused_variables)]
-fn pvr(line: &str, vrs: &mut Vec<Var>, index: usize) -> Result<(), String> {
+fn pvr(
+    line: &str,
+    vrs: &mut Vec<Var>,
+    index: usize,
+    context: &Context,
+) -> Result<Option<Stmt>, String> {
+    let declared = vrs.len();
     let acd = &line[4..];
     let mut parts = acd.split('=').map(|s| s.trim());
     let name = parts
@@ -304,7 +506,28 @@ fn pvr(line: &str, vrs: &mut Vec<Var>, index: usize) -> Result<(), String> {
         .next()
         .ok_or_else(|| "Missing variable value".to_string())?;
 
-    if val.starts_with('\"') && val.ends_with('\"') {
+    if let Some(resource) = resource_name(val) {
+        check_resource(resource, context.resources)?;
+        vrs.push(Var {
+            name: name.to_string(),
+            val: val.to_string(),
+            vt: VT::Str,
+        });
+    } else if let Some((id, args)) = builtin_call(val) {
+        check_value_builtin(id, &args, vrs, context.capabilities)?;
+        vrs.push(Var {
+            name: name.to_string(),
+            val: val.to_string(),
+            vt: VT::Str,
+        });
+    } else if let Some((host, args)) = host_call(val, context.hosts) {
+        check_arguments(host, &args, vrs)?;
+        vrs.push(Var {
+            name: name.to_string(),
+            val: val.to_string(),
+            vt: VT::Host,
+        });
+    } else if val.starts_with('\"') && val.ends_with('\"') {
         vrs.push(Var {
             name: name.to_string(),
             val: val.to_string(),
@@ -340,5 +563,44 @@ fn pvr(line: &str, vrs: &mut Vec<Var>, index: usize) -> Result<(), String> {
         return Err(format!("Unknown Type for: {}", line.bold().green()));
     }
 
-    Ok(())
+    Ok(vrs.get(declared).map(|var| Stmt::Let {
+        name: var.name.clone(),
+        val: var.val.clone(),
+        vt: var.vt.clone(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checked(source: &str, defines: &[(&str, &str)]) -> Result<Vec<CheckedLine>, String> {
+        let code: Vec<String> = source.lines().map(String::from).collect();
+        let defines: Vec<(String, String)> = defines
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut checker = Checker::new(&[], &Capabilities::default());
+        check_program(&mut checker, &code, &defines)
+    }
+
+    #[test]
+    fn defines_are_declared_before_the_program() {
+        let lines = checked("echoln(URL, N)", &[("URL", "\"http://x\""), ("N", "5")]).unwrap();
+        assert_eq!(lines.iter().map(|l| l.index).collect::<Vec<_>>(), [0, 0, 1]);
+        assert!(matches!(
+            &lines[0].stmt,
+            Stmt::Let { name, val, vt: VT::Str } if name == "URL" && val == "\"http://x\""
+        ));
+        assert!(matches!(&lines[1].stmt, Stmt::Let { vt: VT::Int, .. }));
+    }
+
+    #[test]
+    fn undefined_defines_are_errors() {
+        let err = checked("echoln(DEBUG)", &[("N", "5")]).unwrap_err();
+        assert!(err.ends_with("at code (1): echoln(DEBUG)"), "{}", err);
+
+        let err = checked("echoln(N)", &[("N", "5 5")]).unwrap_err();
+        assert!(err.ends_with("in define N"), "{}", err);
+    }
 }
@@ -0,0 +1,289 @@
+use crate::bytecode::{Function, HostCall, Op, Program, Value};
+use crate::check::{builtin_call, call_parts, resource_name, CheckedLine, Stmt, VT};
+
+pub struct CodegenError {
+    pub index: usize,
+    pub msg: String,
+}
+
+/// Instructions of one code block (top level or a function body) with line
+/// marks relative to the start of the block.
+#[derive(Default)]
+struct Block {
+    code: Vec<Op>,
+    lines: Vec<(u32, u32)>,
+}
+
+/// Lowers the statements returned by `check` into a bytecode program. The
+/// `(pc, line)` table is only kept when `line_table` is set.
+pub fn lower(checked: &[CheckedLine], line_table: bool) -> Result<Program, CodegenError> {
+    let mut program = Program::default();
+
+    // Functions may be called before their definition, so number them first
+    for line in checked {
+        if let Stmt::Fn { name, .. } = &line.stmt {
+            program.fns.push(Function {
+                name: name.clone(),
+                entry: 0,
+            });
+        }
+    }
+
+    let mut main = Block::default();
+    let mut bodies: Vec<Block> = (0..program.fns.len()).map(|_| Block::default()).collect();
+    let mut current: Option<usize> = None;
+    let mut next_fn = 0;
+
+    for line in checked {
+        let err = |msg: &str| CodegenError {
+            index: line.index,
+            msg: msg.to_string(),
+        };
+
+        if let Stmt::Fn { has_body, .. } = &line.stmt {
+            if current.is_some() {
+                return Err(err("Nested function definitions are not supported"));
+            }
+            if *has_body {
+                current = Some(next_fn);
+            } else {
+                // The implicit return of `ON f(){}` belongs to the ON line
+                bodies[next_fn].lines.push((0, line.index as u32));
+            }
+            next_fn += 1;
+            continue;
+        }
+        if let Stmt::End = &line.stmt {
+            match current.take() {
+                Some(i) => {
+                    // The implicit return belongs to the closing '}'
+                    let body = &mut bodies[i];
+                    body.lines.push((body.code.len() as u32, line.index as u32));
+                }
+                None => return Err(err("Unexpected '}' outside of a function body")),
+            }
+            continue;
+        }
+
+        let block = match current {
+            Some(i) => &mut bodies[i],
+            None => &mut main,
+        };
+        block
+            .lines
+            .push((block.code.len() as u32, line.index as u32));
+
+        match &line.stmt {
+            Stmt::Let { name, val, .. } if resource_name(val).is_some() => {
+                let resource = resource_name(val).unwrap_or_default().to_string();
+                let c = constant(&mut program.consts, Value::Str(resource));
+                let v = variable(&mut program.vars, name);
+                block.code.push(Op::Resource(c));
+                block.code.push(Op::Store(v));
+            }
+            Stmt::Let { name, val, vt } if builtin_call(val).is_some() || matches!(vt, VT::Host) => {
+                push_argument(&mut program, &mut block.code, val).map_err(|e| err(&e))?;
+                let v = variable(&mut program.vars, name);
+                block.code.push(Op::Store(v));
+            }
+            Stmt::Let { name, val, vt } => {
+                let value = match vt {
+                    VT::Str => Value::Str(unquote(val)),
+                    VT::Int => Value::Int(val.parse().map_err(|_| err("Invalid integer"))?),
+                    VT::Float => Value::Float(val.parse().map_err(|_| err("Invalid float"))?),
+                    VT::RT | VT::Host => Value::Str(val.clone()),
+                };
+                let c = constant(&mut program.consts, value);
+                let v = variable(&mut program.vars, name);
+                block.code.push(Op::Const(c));
+                block.code.push(Op::Store(v));
+            }
+            Stmt::Echo(args) => {
+                for arg in args {
+                    push_argument(&mut program, &mut block.code, arg).map_err(|e| err(&e))?;
+                }
+                block.code.push(Op::Echo(args.len() as u32));
+            }
+            Stmt::Builtin { id, args } => {
+                for arg in args {
+                    push_argument(&mut program, &mut block.code, arg).map_err(|e| err(&e))?;
+                }
+                block.code.push(Op::Builtin(*id as u32));
+            }
+            Stmt::Host { name, args } => {
+                for arg in args {
+                    push_argument(&mut program, &mut block.code, arg).map_err(|e| err(&e))?;
+                }
+                let h = host(&mut program.hosts, name, args.len(), false);
+                block.code.push(Op::Host(h));
+            }
+            Stmt::Call(name) => match program.fns.iter().position(|f| f.name == *name) {
+                Some(f) => block.code.push(Op::Call(f as u32)),
+                None => return Err(err(&format!("Undefined function call '{}'", name))),
+            },
+            Stmt::Fn { .. } | Stmt::End => unreachable!(),
+        }
+    }
+
+    if current.is_some() {
+        let index = checked
+            .iter()
+            .rev()
+            .find(|l| matches!(l.stmt, Stmt::Fn { .. }))
+            .map_or(0, |l| l.index);
+        return Err(CodegenError {
+            index,
+            msg: "Function body is missing its closing '}'".to_string(),
+        });
+    }
+
+    append_block(&mut program, main, line_table);
+    program.code.push(Op::Halt);
+
+    for (i, body) in bodies.into_iter().enumerate() {
+        program.fns[i].entry = program.code.len() as u32;
+        append_block(&mut program, body, line_table);
+        program.code.push(Op::Ret);
+    }
+
+    Ok(program)
+}
+
+fn append_block(program: &mut Program, block: Block, line_table: bool) {
+    let base = program.code.len() as u32;
+    if line_table {
+        program
+            .lines
+            .extend(block.lines.iter().map(|(pc, line)| (base + pc, *line)));
+    }
+    program.code.extend(block.code);
+}
+
+/// Emits the instructions that push one `echoln`, builtin or host argument: a
+/// resource, a builtin or host call, a string literal or a variable.
+fn push_argument(program: &mut Program, code: &mut Vec<Op>, arg: &str) -> Result<(), String> {
+    if let Some(resource) = resource_name(arg) {
+        let c = constant(&mut program.consts, Value::Str(resource.to_string()));
+        code.push(Op::Resource(c));
+    } else if let Some((id, args)) = builtin_call(arg) {
+        for arg in &args {
+            push_argument(program, code, arg)?;
+        }
+        code.push(Op::Builtin(id as u32));
+    } else if let Some((name, args)) = call_parts(arg) {
+        for arg in &args {
+            push_argument(program, code, arg)?;
+        }
+        let h = host(&mut program.hosts, name, args.len(), true);
+        code.push(Op::Host(h));
+    } else if arg.starts_with('"') || arg.starts_with('\'') {
+        let c = constant(&mut program.consts, Value::Str(unquote(arg)));
+        code.push(Op::Const(c));
+    } else {
+        match program.vars.iter().position(|v| v == arg) {
+            Some(v) => code.push(Op::Load(v as u32)),
+            None => return Err(format!("Use of undeclared variable '{}'", arg)),
+        }
+    }
+    Ok(())
+}
+
+pub fn unquote(literal: &str) -> String {
+    if literal.len() >= 2 {
+        literal[1..literal.len() - 1].to_string()
+    } else {
+        literal.to_string()
+    }
+}
+
+fn constant(consts: &mut Vec<Value>, value: Value) -> u32 {
+    match consts.iter().position(|c| *c == value) {
+        Some(i) => i as u32,
+        None => {
+            consts.push(value);
+            (consts.len() - 1) as u32
+        }
+    }
+}
+
+fn host(hosts: &mut Vec<HostCall>, name: &str, args: usize, returns: bool) -> u32 {
+    let call = HostCall {
+        name: name.to_string(),
+        args: args as u32,
+        returns,
+    };
+    match hosts.iter().position(|h| *h == call) {
+        Some(i) => i as u32,
+        None => {
+            hosts.push(call);
+            (hosts.len() - 1) as u32
+        }
+    }
+}
+
+fn variable(vars: &mut Vec<String>, name: &str) -> u32 {
+    match vars.iter().position(|v| v == name) {
+        Some(i) => i as u32,
+        None => {
+            vars.push(name.to_string());
+            (vars.len() - 1) as u32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caps::Capabilities;
+    use crate::check::{check_program, Checker};
+
+    fn lowered(source: &str, line_table: bool) -> Result<Program, CodegenError> {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let mut checker = Checker::new(&[], &Capabilities::default());
+        let checked = check_program(&mut checker, &lines, &[]).unwrap();
+        lower(&checked, line_table)
+    }
+
+    #[test]
+    fn functions_follow_the_top_level_code() {
+        let source = "may x = 5\ngreet()\nON greet(){\n    echoln(\"x is \", x)\n}";
+        let program = lowered(source, true).ok().unwrap();
+        assert_eq!(
+            program.code,
+            [
+                Op::Const(0),
+                Op::Store(0),
+                Op::Call(0),
+                Op::Halt,
+                Op::Const(1),
+                Op::Load(0),
+                Op::Echo(2),
+                Op::Ret,
+            ]
+        );
+        assert_eq!(
+            program.consts,
+            [Value::Int(5), Value::Str("x is ".to_string())]
+        );
+        assert_eq!(program.fns[0].entry, 4);
+        assert_eq!(program.lines, [(0, 1), (2, 2), (4, 4), (7, 5)]);
+        assert!(lowered(source, false).ok().unwrap().lines.is_empty());
+    }
+
+    #[test]
+    fn constants_and_variables_are_shared() {
+        let source = "may a = \"v\"\nmay b = \"v\"\necholn(a, 'v', b)";
+        let program = lowered(source, false).ok().unwrap();
+        assert_eq!(program.vars, ["a", "b"]);
+        assert_eq!(program.consts, [Value::Str("v".to_string())]);
+    }
+
+    #[test]
+    fn unterminated_body() {
+        let e = lowered("ON f(){\n    echoln('x')", true).err().unwrap();
+        assert_eq!(
+            (e.index, e.msg.as_str()),
+            (1, "Function body is missing its closing '}'")
+        );
+    }
+}
@@ -0,0 +1,699 @@
+use bimble::bytecode::Limits;
+use bimble::caps::Capabilities;
+use bimble::exe::EmbedMode;
+use bimble::payload::Codec;
+use bimble::preproc::TARGETS;
+use colored::Colorize;
+use std::{fs, process::exit, time::Duration};
+
+pub struct CompilerConfig {
+    pub name: String,
+    pub authors: String,
+    pub version: String,
+    pub defines: Vec<(String, String)>,
+    pub compress: Codec,
+    pub obfuscate: bool,
+    pub embed: EmbedMode,
+    pub resources: Vec<String>,
+    pub pre_build: Vec<String>,
+    pub post_build: Vec<String>,
+    pub dev: BuildProfile,
+    pub release: BuildProfile,
+    pub targets: Vec<TargetConfig>,
+    /// Runtime limits from `[limits]`, enforced by the VM and the interpreter.
+    pub limits: Limits,
+    /// What builtins may touch, from `[capabilities]`.
+    pub capabilities: Capabilities,
+}
+
+/// Per-platform settings from a `[target.<platform>]` section.
+pub struct TargetConfig {
+    pub platform: &'static str,
+    pub output_name: Option<String>,
+    /// `.ico` file, relative to the project folder, embedded in Windows builds.
+    pub icon: Option<String>,
+}
+
+/// Payload transformations applied by `compile_project` for one build profile.
+/// Comment, blank-line and whitespace stripping only apply to legacy source
+/// payloads; bytecode payloads honour `line_table` and always run the checks.
+#[derive(Clone, Debug)]
+pub struct BuildProfile {
+    pub name: &'static str,
+    pub strip_comments: bool,
+    pub strip_blank_lines: bool,
+    pub minify: bool,
+    pub line_table: bool,
+    pub checks: bool,
+}
+
+impl BuildProfile {
+    pub fn dev() -> Self {
+        Self {
+            name: "dev",
+            strip_comments: false,
+            strip_blank_lines: false,
+            minify: false,
+            line_table: true,
+            checks: true,
+        }
+    }
+
+    pub fn release() -> Self {
+        Self {
+            name: "release",
+            strip_comments: true,
+            strip_blank_lines: true,
+            minify: true,
+            line_table: false,
+            checks: true,
+        }
+    }
+}
+
+impl CompilerConfig {
+    pub fn new() -> Self {
+        Self {
+            name: String::new(),
+            authors: String::new(),
+            version: String::new(),
+            defines: Vec::new(),
+            compress: Codec::None,
+            obfuscate: false,
+            embed: EmbedMode::Append,
+            resources: Vec::new(),
+            pre_build: Vec::new(),
+            post_build: Vec::new(),
+            dev: BuildProfile::dev(),
+            release: BuildProfile::release(),
+            targets: TARGETS
+                .iter()
+                .map(|platform| TargetConfig {
+                    platform,
+                    output_name: None,
+                    icon: None,
+                })
+                .collect(),
+            limits: Limits::default(),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    pub fn target(&self, platform: &str) -> &TargetConfig {
+        self.targets
+            .iter()
+            .find(|t| t.platform == platform)
+            .expect("unknown target platform")
+    }
+
+    /// File name of the artifact built for `platform`, honouring `OutputName`.
+    pub fn artifact_name(&self, platform: &str) -> String {
+        match &self.target(platform).output_name {
+            Some(name) => name.clone(),
+            None if platform == "windows" => format!("{}.exe", self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    pub fn profile(&self, release: bool) -> &BuildProfile {
+        if release {
+            &self.release
+        } else {
+            &self.dev
+        }
+    }
+
+    /// Adds or replaces a compile-time define; later definitions win.
+    pub fn set_define(&mut self, key: String, value: String) {
+        self.defines.retain(|(k, _)| *k != key);
+        self.defines.push((key, value));
+    }
+
+    /// Renders the defines as the `may` declarations injected ahead of main.bb.
+    pub fn define_lines(&self) -> Vec<String> {
+        self.defines
+            .iter()
+            .map(|(k, v)| format!("may {} = {}", k, v))
+            .collect()
+    }
+}
+
+/// Parses a `KEY=VALUE` define. Bare values that are not numbers are quoted so
+/// they become string constants. bimble strings have no escapes, so a `"` is
+/// only allowed as the pair wrapping the whole value.
+pub fn parse_define(text: &str) -> Result<(String, String), String> {
+    let (key, value) = text
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VALUE in define '{}'", text))?;
+    let (key, value) = (key.trim(), value.trim());
+
+    let mut chars = key.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if !valid_start || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid define name '{}'", key));
+    }
+
+    if value.parse::<f64>().is_ok() {
+        return Ok((key.to_string(), value.to_string()));
+    }
+    let text = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    if text.contains('"') {
+        return Err(format!(
+            "Invalid define value '{}' for {}: '\"' may only wrap the whole value",
+            value, key
+        ));
+    }
+    let value = format!("\"{}\"", text);
+
+    Ok((key.to_string(), value))
+}
+
+/// Sets one runtime limit from a `[limits]` key or `--max-<key>` flag.
+/// `Memory` takes bytes with an optional K, M or G suffix; `Time` takes
+/// milliseconds, or seconds with an `s` suffix. The VM reads the clock every
+/// 256 instructions and after each builtin or host call, so a program can run
+/// past its `Time` by that much, and a blocking call is only stopped once it
+/// returns.
+pub fn set_limit(limits: &mut Limits, key: &str, value: &str) -> Result<(), String> {
+    let value = value.trim().to_ascii_lowercase();
+    let number = |text: &str| match text.trim().parse::<u64>() {
+        Ok(0) | Err(_) => Err(format!(
+            "Invalid {} limit '{}', expected a positive number",
+            key, value
+        )),
+        Ok(n) => Ok(n),
+    };
+    match key.to_ascii_lowercase().as_str() {
+        "instructions" => limits.instructions = Some(number(&value)?),
+        "depth" => {
+            let depth = number(&value)?;
+            limits.depth = Some(
+                u32::try_from(depth).map_err(|_| format!("Depth limit {} is too large", depth))?,
+            );
+        }
+        "memory" => {
+            let bytes = value.trim_end_matches('b');
+            let (digits, scale) = match bytes.char_indices().last() {
+                Some((i, 'k')) => (&bytes[..i], 1 << 10),
+                Some((i, 'm')) => (&bytes[..i], 1 << 20),
+                Some((i, 'g')) => (&bytes[..i], 1 << 30),
+                _ => (bytes, 1),
+            };
+            let memory = number(digits)?;
+            limits.memory = Some(
+                memory
+                    .checked_mul(scale)
+                    .ok_or_else(|| format!("Memory limit '{}' is too large", value))?,
+            );
+        }
+        "time" => {
+            let time = match value.strip_suffix("ms") {
+                Some(ms) => Duration::from_millis(number(ms)?),
+                None => match value.strip_suffix('s') {
+                    Some(secs) => Duration::from_secs(number(secs)?),
+                    None => Duration::from_millis(number(&value)?),
+                },
+            };
+            limits.time = Some(time);
+        }
+        _ => {
+            return Err(format!(
+                "Invalid limit '{}'. Expected 'Instructions', 'Depth', 'Memory', or 'Time'.",
+                key
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Names reserved by Windows regardless of extension.
+const RESERVED_NAMES: [&str; 26] = [
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM0", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks that an artifact name is a single portable file name: ASCII letters,
+/// digits, '.', '_' and '-', not starting with '.' or '-', not ending with '.',
+/// and not a Windows-reserved device name.
+pub fn validate_artifact_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Artifact name is empty".to_string());
+    }
+    if name.len() > 64 {
+        return Err(format!(
+            "Artifact name '{}' is longer than 64 characters",
+            name
+        ));
+    }
+    let stem = name.split('.').next().unwrap_or(name).to_ascii_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        return Err(format!("Artifact name '{}' is reserved on Windows", name));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+    {
+        return Err(format!(
+            "Artifact name '{}' contains invalid character {:?}",
+            name, c
+        ));
+    }
+    if name.starts_with('.') || name.starts_with('-') || name.ends_with('.') {
+        return Err(format!(
+            "Artifact name '{}' must not start with '.' or '-' or end with '.'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+enum Section {
+    Project,
+    Profile(bool),
+    Target(usize),
+    Limits,
+    Capabilities,
+}
+
+/// Like `read_config`, but reports the error and exits.
+pub fn parse_config(project_path: &str) -> CompilerConfig {
+    match read_config(project_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e.red());
+            exit(-1);
+        }
+    }
+}
+
+/// Reads and validates cfg.bcf in `project_path`.
+pub fn read_config(project_path: &str) -> Result<CompilerConfig, String> {
+    let config_file = format!("{}/{}", project_path, "cfg.bcf");
+
+    let config_content = fs::read_to_string(&config_file).map_err(|err| {
+        format!(
+            "Unable to read config file: {}. Please check if it exists and is accessible. Error details: {}",
+            config_file, err
+        )
+    })?;
+
+    let mut parsed_config = CompilerConfig::new();
+    let mut section = Section::Project;
+    for (line_number, line) in config_content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            section = match trimmed[1..trimmed.len() - 1]
+                .trim()
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "profile.dev" => Section::Profile(false),
+                "profile.release" => Section::Profile(true),
+                "limits" => Section::Limits,
+                "capabilities" => Section::Capabilities,
+                other if other.starts_with("target.") => {
+                    match TARGETS.iter().position(|t| *t == &other[7..]) {
+                        Some(i) => Section::Target(i),
+                        None => return Err(config_error(
+                            line_number,
+                            &format!(
+                                "Unknown target section '{}', expected one of {}",
+                                other,
+                                TARGETS.join(", ")
+                            ),
+                        )),
+                    }
+                }
+                other => {
+                    return Err(config_error(
+                        line_number,
+                        &format!(
+                            "Unknown section '{}'. Expected '[profile.dev]', '[profile.release]', '[target.<platform>]', '[limits]' or '[capabilities]'.",
+                            other
+                        ),
+                    ))
+                }
+            };
+            continue;
+        }
+
+        let mut parts: Vec<&str> = line.split(':').map(|s| s.trim()).collect();
+
+        // Hook commands, define values and paths are free-form and may contain
+        // ':' themselves, as in `Define: URL=http://x` or `Read: C:\data`
+        if parts.len() > 2
+            && matches!(
+                parts[0].to_ascii_uppercase().as_str(),
+                "PREBUILD" | "POSTBUILD" | "DEFINE" | "RESOURCES" | "ICON" | "READ" | "WRITE"
+            )
+        {
+            if let Some((key, value)) = line.split_once(':') {
+                parts = vec![key.trim(), value.trim()];
+            }
+        }
+
+        if parts.len() > 2 {
+            return Err(config_error(
+                line_number,
+                "Too many values, expected only a single key-value pair.",
+            ));
+        } else if parts.len() == 2 {
+            let key = parts[0].to_ascii_uppercase();
+            let value = parts[1].to_string();
+
+            match section {
+                Section::Project => match key.as_str() {
+                    "NAME" => {
+                        if let Err(e) = validate_artifact_name(&value) {
+                            return Err(config_error(line_number, &e));
+                        }
+                        parsed_config.name = value
+                    }
+                    "AUTHORS" => parsed_config.authors = value,
+                    "VER" => parsed_config.version = value,
+                    "COMPRESS" => match Codec::parse(&value) {
+                        Ok(codec) => parsed_config.compress = codec,
+                        Err(e) => return Err(config_error(line_number, &e)),
+                    },
+                    "OBFUSCATE" => parsed_config.obfuscate = parse_flag(&value, line_number)?,
+                    "EMBED" => match EmbedMode::parse(&value) {
+                        Ok(mode) => parsed_config.embed = mode,
+                        Err(e) => return Err(config_error(line_number, &e)),
+                    },
+                    "RESOURCES" => parsed_config.resources.extend(
+                        value
+                            .split(',')
+                            .map(|p| p.trim().to_string())
+                            .filter(|p| !p.is_empty()),
+                    ),
+                    "PREBUILD" => parsed_config.pre_build.push(value),
+                    "POSTBUILD" => parsed_config.post_build.push(value),
+                    "DEFINE" => match parse_define(&value) {
+                        Ok((k, v)) => parsed_config.set_define(k, v),
+                        Err(e) => return Err(config_error(line_number, &e)),
+                    },
+                    _ => {
+                        return Err(config_error(
+                            line_number,
+                            &format!(
+                                "Invalid key '{}'. Expected 'Name', 'Authors', 'Ver', 'Define', 'Compress', 'Obfuscate', 'Embed', 'Resources', 'PreBuild', or 'PostBuild'.",
+                                key
+                            ),
+                        ))
+                    }
+                },
+                Section::Profile(release) => {
+                    let flag = parse_flag(&value, line_number)?;
+                    let profile = if release {
+                        &mut parsed_config.release
+                    } else {
+                        &mut parsed_config.dev
+                    };
+                    match key.as_str() {
+                        "STRIPCOMMENTS" => profile.strip_comments = flag,
+                        "STRIPBLANKLINES" => profile.strip_blank_lines = flag,
+                        "MINIFY" => profile.minify = flag,
+                        "LINETABLE" => profile.line_table = flag,
+                        "CHECKS" => profile.checks = flag,
+                        _ => {
+                            return Err(config_error(
+                                line_number,
+                                &format!(
+                                    "Invalid profile key '{}'. Expected 'StripComments', 'StripBlankLines', 'Minify', 'LineTable', or 'Checks'.",
+                                    key
+                                ),
+                            ))
+                        }
+                    }
+                }
+                Section::Target(i) => match key.as_str() {
+                    "OUTPUTNAME" => {
+                        if let Err(e) = validate_artifact_name(&value) {
+                            return Err(config_error(line_number, &e));
+                        }
+                        parsed_config.targets[i].output_name = Some(value)
+                    }
+                    "ICON" if parsed_config.targets[i].platform == "windows" => {
+                        parsed_config.targets[i].icon = Some(value)
+                    }
+                    "ICON" => {
+                        return Err(config_error(line_number, "Icon is only supported in [target.windows]"))
+                    }
+                    _ => return Err(config_error(
+                        line_number,
+                        &format!(
+                            "Invalid target key '{}'. Expected 'OutputName' or 'Icon'.",
+                            key
+                        ),
+                    )),
+                },
+                Section::Limits => {
+                    if let Err(e) = set_limit(&mut parsed_config.limits, parts[0], &value) {
+                        return Err(config_error(line_number, &e));
+                    }
+                }
+                Section::Capabilities => {
+                    let capabilities = &mut parsed_config.capabilities;
+                    let paths = value
+                        .split(',')
+                        .map(|p| p.trim().to_string())
+                        .filter(|p| !p.is_empty());
+                    match key.as_str() {
+                        "READ" => capabilities.read.extend(paths),
+                        "WRITE" => capabilities.write.extend(paths),
+                        "PROCESS" => capabilities.process = parse_flag(&value, line_number)?,
+                        "ENV" => capabilities.env = parse_flag(&value, line_number)?,
+                        "NETWORK" => capabilities.network = parse_flag(&value, line_number)?,
+                        _ => return Err(config_error(
+                            line_number,
+                            &format!(
+                                "Invalid capability '{}'. Expected 'Read', 'Write', 'Process', 'Env', or 'Network'.",
+                                key
+                            ),
+                        )),
+                    }
+                }
+            }
+        } else if parts.len() == 1 && !parts[0].is_empty() {
+            return Err(config_error(
+                line_number,
+                &format!(
+                    "Invalid term '{}'. Expected 'NAME', 'AUTHORS', or 'VERSION'.",
+                    parts[0]
+                ),
+            ));
+        }
+    }
+
+    // Check if any of the required keys are missing
+    if parsed_config.name.is_empty() {
+        return Err("Error: Missing 'NAME' key in config file.".to_string());
+    }
+    if parsed_config.authors.is_empty() {
+        return Err("Error: Missing 'AUTHORS' key in config file.".to_string());
+    }
+    if parsed_config.version.is_empty() {
+        return Err("Error: Missing 'VERSION' key in config file.".to_string());
+    }
+
+    Ok(parsed_config)
+}
+
+fn config_error(line_number: usize, msg: &str) -> String {
+    format!("Error on line {}: {}", line_number + 1, msg)
+}
+
+fn parse_flag(value: &str, line_number: usize) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(config_error(
+            line_number,
+            &format!("Invalid value '{}'. Expected 'true' or 'false'.", value),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portable_artifact_names() {
+        for name in [
+            "app", "my-app_2", "app.exe", "a.b.c", "console", "com10", "app.con",
+        ] {
+            assert!(validate_artifact_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_artifact_names() {
+        let long = "a".repeat(65);
+        for name in [
+            "",
+            &long,
+            "my app",
+            "../app",
+            "dir/app",
+            "app\\x",
+            "ünïcode",
+            ".hidden",
+            "-flag",
+            "app.",
+            "CON",
+            "nul.exe",
+            "com1",
+            "COM0",
+            "lpt0.bin",
+            "con.txt",
+            "Aux.tar.gz",
+        ] {
+            assert!(validate_artifact_name(name).is_err(), "{}", name);
+        }
+        // Reserved names are caught before the character check as well
+        for name in ["CONIN$", "conout$.exe"] {
+            assert_eq!(
+                validate_artifact_name(name),
+                Err(format!("Artifact name '{}' is reserved on Windows", name))
+            );
+        }
+    }
+
+    /// Writes `cfg` after the required keys as the cfg.bcf of a scratch
+    /// project and reads it back.
+    fn read_project(name: &str, cfg: &str) -> Result<CompilerConfig, String> {
+        let dir =
+            std::env::temp_dir().join(format!("bimble-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cfg = format!("Name: app\nAuthors: VS\nVer: 1\n{}", cfg);
+        fs::write(dir.join("cfg.bcf"), cfg).unwrap();
+        let config = read_config(&dir.display().to_string());
+        fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    #[test]
+    fn profile_overrides() {
+        let config = read_project(
+            "profile",
+            "[profile.release]\nLineTable: true\n[profile.dev]\nMinify: true\n",
+        )
+        .unwrap();
+        let (dev, release) = (config.profile(false), config.profile(true));
+        assert_eq!(
+            (dev.name, dev.minify, dev.strip_comments),
+            ("dev", true, false)
+        );
+        assert_eq!(
+            (release.name, release.line_table, release.minify),
+            ("release", true, true)
+        );
+        assert!(read_project("bad-profile", "[profile.release]\nFast: true\n").is_err());
+    }
+
+    #[test]
+    fn defines() {
+        assert_eq!(parse_define("N=5"), Ok(("N".to_string(), "5".to_string())));
+        assert_eq!(
+            parse_define(" URL = http://x:8080 "),
+            Ok(("URL".to_string(), "\"http://x:8080\"".to_string()))
+        );
+        assert_eq!(
+            parse_define("S=\"a = b\""),
+            Ok(("S".to_string(), "\"a = b\"".to_string()))
+        );
+        assert_eq!(
+            parse_define("E=\"\""),
+            Ok(("E".to_string(), "\"\"".to_string()))
+        );
+        for bad in ["N", "=1", "2N=1", "my-flag=1"] {
+            assert!(parse_define(bad).is_err(), "{}", bad);
+        }
+        for bad in [
+            "MSG=\"a\"b\"",
+            "X=\"",
+            "X=\"open",
+            "X=close\"",
+            "X=say \"hi\"",
+        ] {
+            let err = parse_define(bad).unwrap_err();
+            assert!(err.starts_with("Invalid define value"), "{}: {}", bad, err);
+        }
+        assert!(read_project("bad-define", "Define: X=\"\n").is_err());
+
+        let config =
+            read_project("define", "Define: URL=http://x\nDefine: N=1\nDefine: N=2\n").unwrap();
+        assert_eq!(
+            config.define_lines(),
+            ["may URL = \"http://x\"", "may N = 2"]
+        );
+    }
+
+    #[test]
+    fn paths_may_contain_colons() {
+        let config = read_project(
+            "paths",
+            "Resources: C:\\assets\\*.txt, data/*\n[target.windows]\nIcon: D:\\art\\app.ico\n[capabilities]\nRead: C:\\data, notes\nWrite: D:\\out\n",
+        )
+        .unwrap();
+        assert_eq!(config.resources, ["C:\\assets\\*.txt", "data/*"]);
+        assert_eq!(
+            config.target("windows").icon.as_deref(),
+            Some("D:\\art\\app.ico")
+        );
+        assert_eq!(config.capabilities.read, ["C:\\data", "notes"]);
+        assert_eq!(config.capabilities.write, ["D:\\out"]);
+        // Other keys still take a single value
+        assert!(read_project("colon", "Authors: A: B\n").is_err());
+    }
+
+    #[test]
+    fn limit_values() {
+        let mut limits = Limits::default();
+        for (key, value) in [
+            ("Instructions", "1000"),
+            ("depth", "64"),
+            ("Memory", "2M"),
+            ("Time", "2s"),
+        ] {
+            set_limit(&mut limits, key, value).unwrap();
+        }
+        assert_eq!(
+            limits,
+            Limits {
+                instructions: Some(1000),
+                depth: Some(64),
+                memory: Some(2 << 20),
+                time: Some(Duration::from_secs(2)),
+            }
+        );
+        set_limit(&mut limits, "memory", "512kb").unwrap();
+        set_limit(&mut limits, "time", "250ms").unwrap();
+        assert_eq!(limits.memory, Some(512 << 10));
+        assert_eq!(limits.time, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let mut limits = Limits::default();
+        for (key, value) in [
+            ("Instructions", "0"),
+            ("Depth", "-1"),
+            ("Depth", "99999999999"),
+            ("Memory", "lots"),
+            ("Memory", "99999999999G"),
+            ("Time", "1h"),
+            ("Speed", "1"),
+        ] {
+            assert!(set_limit(&mut limits, key, value).is_err(), "{}", key);
+        }
+        assert!(limits.is_unlimited());
+    }
+}
@@ -0,0 +1,473 @@
+use crate::config::read_config;
+use crate::debug::{load_debugger, Breakpoint, Debugger, Resume, Stop};
+use bimble::vm::Captured;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// The program runs on a single thread, reported to the client with this id.
+const THREAD_ID: u64 = 1;
+/// `variablesReference` of the only scope; bimble variables are global.
+const GLOBALS: u64 = 1;
+/// Instructions run between two looks at the request queue while the program
+/// is running, so `pause` and `disconnect` are answered promptly.
+const STEP_SLICE: usize = 10_000;
+
+/// A launched program.
+struct Session {
+    debugger: Debugger,
+    source: Vec<String>,
+    /// Absolute path of main.bb, used as the source of every frame.
+    path: String,
+    /// DAP id of every entry of `debugger.breakpoints`, in the same order.
+    breakpoint_ids: Vec<u64>,
+    /// `echoln` output of the debuggee, collected between requests and sent
+    /// to the client as `output` events. stdout carries the protocol itself.
+    output: Captured,
+    stop_on_entry: bool,
+    /// Set once `configurationDone` started execution.
+    started: bool,
+    /// Set while the program runs between requests.
+    running: Option<Running>,
+}
+
+/// A `resume` in progress, advanced a slice at a time.
+struct Running {
+    mode: Resume,
+    /// Call depth when it started.
+    depth: usize,
+    /// `reason` of the stopped event unless a breakpoint is hit.
+    reason: &'static str,
+}
+
+/// Serves the Debug Adapter Protocol on stdin/stdout until the client
+/// disconnects.
+pub fn dap() {
+    // Requests are read on their own thread so they can arrive while the
+    // program runs
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(io::stdin());
+        while let Some(request) = read(&mut input) {
+            if sender.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server = Server {
+        requests,
+        out: io::stdout(),
+        seq: 0,
+        next_breakpoint_id: 1,
+        session: None,
+    };
+    while let Some(request) = server.next_request() {
+        if !server.handle(&request) {
+            break;
+        }
+    }
+}
+
+/// Reads one `Content-Length` framed message; `None` at end of input.
+fn read(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+struct Server {
+    requests: Receiver<Value>,
+    out: io::Stdout,
+    seq: u64,
+    /// Breakpoint ids are never reused, as clients match events by id.
+    next_breakpoint_id: u64,
+    session: Option<Session>,
+}
+
+impl Server {
+    /// Waits for the next request, running the program in slices meanwhile;
+    /// `None` once the client closed its end.
+    fn next_request(&mut self) -> Option<Value> {
+        loop {
+            if self.session.as_ref().is_none_or(|s| s.running.is_none()) {
+                return self.requests.recv().ok();
+            }
+            match self.requests.try_recv() {
+                Ok(request) => return Some(request),
+                Err(TryRecvError::Empty) => self.advance(),
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let _ = write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.out.flush();
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(msg) => response["message"] = json!(msg),
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    /// Handles one request; returns false once the client has disconnected.
+    fn handle(&mut self, request: &Value) -> bool {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or("");
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+            })),
+            "launch" => {
+                let result = self.launch(args);
+                // The client shows output events in its debug console
+                if let Err(msg) = &result {
+                    let output = format!("{}\n", msg);
+                    self.event("output", json!({"category": "stderr", "output": output}));
+                }
+                result
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})));
+                if command == "terminate" && self.session.take().is_some() {
+                    self.event("terminated", json!({}));
+                    return true;
+                }
+                return false;
+            }
+            _ => match &mut self.session {
+                Some(session) => session.request(command, args, &mut self.next_breakpoint_id),
+                None => Err(format!("'{}' needs a launched program", command)),
+            },
+        };
+        let ok = result.is_ok();
+        self.respond(request, result);
+
+        match command {
+            // Breakpoints need the program, so configuration starts after launch
+            "launch" if ok => self.event("initialized", json!({})),
+            "configurationDone" if ok => {
+                let session = self.session.as_mut().unwrap();
+                session.started = true;
+                let stop = session.debugger.start();
+                match stop {
+                    Stop::Paused {
+                        breakpoint: None, ..
+                    } if !session.stop_on_entry => self.resume(Resume::Continue),
+                    Stop::Paused {
+                        breakpoint: None, ..
+                    } => self.stopped(stop, "entry"),
+                    stop => self.stopped(stop, "breakpoint"),
+                }
+            }
+            "continue" if ok => self.resume(Resume::Continue),
+            "next" if ok => self.resume(Resume::Next),
+            "stepIn" if ok => self.resume(Resume::Step),
+            "stepOut" if ok => self.resume(Resume::Out),
+            "pause" if ok => {
+                let session = self.session.as_mut().unwrap();
+                match &mut session.running {
+                    // Stop before the next statement, where the stack is whole
+                    Some(running) => {
+                        running.mode = Resume::Step;
+                        running.reason = "pause";
+                    }
+                    None => self.event(
+                        "stopped",
+                        json!({"reason": "pause", "threadId": THREAD_ID, "allThreadsStopped": true}),
+                    ),
+                }
+            }
+            _ => (),
+        }
+        true
+    }
+
+    /// Checks and lowers the project named by `program`, without running it.
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let project = args["program"]
+            .as_str()
+            .ok_or("launch needs 'program', the project folder")?;
+        let main = Path::new(project).join("main.bb");
+        let code = fs::read_to_string(&main)
+            .map_err(|e| format!("Cannot read code from '{}': {}", main.display(), e))?;
+        let path = fs::canonicalize(&main)
+            .map_err(|e| e.to_string())?
+            .display()
+            .to_string();
+
+        let config = read_config(project)?;
+        let profile = config.profile(false);
+        let output = Captured::default();
+        let (debugger, source) =
+            load_debugger(project, &code, &config, profile, Box::new(output.clone()))?;
+        self.session = Some(Session {
+            debugger,
+            source,
+            path,
+            breakpoint_ids: Vec::new(),
+            output,
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            started: false,
+            running: None,
+        });
+        Ok(json!({}))
+    }
+
+    /// Starts running the program; `next_request` advances it.
+    fn resume(&mut self, mode: Resume) {
+        let session = self.session.as_mut().unwrap();
+        session.running = Some(Running {
+            mode,
+            depth: session.debugger.vm.frames.len(),
+            reason: match mode {
+                Resume::Continue => "breakpoint",
+                _ => "step",
+            },
+        });
+    }
+
+    /// Runs one slice of the program and reports it if it stopped.
+    fn advance(&mut self) {
+        let session = self.session.as_mut().unwrap();
+        let running = session.running.as_ref().unwrap();
+        let (mode, depth, reason) = (running.mode, running.depth, running.reason);
+        match session.debugger.advance(mode, depth, STEP_SLICE) {
+            Some(stop) => {
+                session.running = None;
+                self.stopped(stop, reason);
+            }
+            None => self.flush_output(),
+        }
+    }
+
+    /// Sends the program's output collected so far to the client.
+    fn flush_output(&mut self) {
+        let session = self.session.as_mut().unwrap();
+        let output = session.output.take();
+        if !output.is_empty() {
+            let text = String::from_utf8_lossy(&output).into_owned();
+            self.event("output", json!({"category": "stdout", "output": text}));
+        }
+    }
+
+    /// Flushes program output, then tells the client why execution stopped.
+    fn stopped(&mut self, stop: Stop, reason: &str) {
+        self.flush_output();
+
+        match stop {
+            Stop::Paused { breakpoint, .. } => {
+                let reason = if breakpoint.is_some() {
+                    "breakpoint"
+                } else {
+                    reason
+                };
+                let mut body = json!({
+                    "reason": reason,
+                    "threadId": THREAD_ID,
+                    "allThreadsStopped": true,
+                });
+                let session = self.session.as_ref().unwrap();
+                if let Some(id) = breakpoint.and_then(|b| session.breakpoint_ids.get(b)) {
+                    body["hitBreakpointIds"] = json!([id]);
+                }
+                self.event("stopped", body);
+            }
+            Stop::Exited => self.exit(0),
+            Stop::Failed { line, msg } => {
+                let session = self.session.as_ref().unwrap();
+                let text = line
+                    .and_then(|l| session.source.get((l as usize).wrapping_sub(1)))
+                    .map_or("", |l| l.trim());
+                let output = format!("Error: {} at code ({}): {}\n", msg, line.unwrap_or(0), text);
+                self.event("output", json!({"category": "stderr", "output": output}));
+                self.exit(-1);
+            }
+        }
+    }
+
+    fn exit(&mut self, code: i32) {
+        self.session = None;
+        self.event("exited", json!({"exitCode": code}));
+        self.event("terminated", json!({}));
+    }
+}
+
+impl Session {
+    /// Requests answered from the paused program; none of them run it.
+    /// New breakpoints take their ids from `next_id`.
+    fn request(&mut self, command: &str, args: &Value, next_id: &mut u64) -> Result<Value, String> {
+        match command {
+            "setBreakpoints" => {
+                let lines = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                if !self.is_main(&args["source"]) {
+                    let breakpoints: Vec<Value> = lines
+                        .iter()
+                        .map(|b| {
+                            json!({
+                                "verified": false,
+                                "line": b["line"],
+                                "message": "Breakpoints can only be set in the project's main.bb",
+                            })
+                        })
+                        .collect();
+                    return Ok(json!({ "breakpoints": breakpoints }));
+                }
+                // Each request replaces every line breakpoint of main.bb
+                self.retain_breakpoints(|b| !matches!(b, Breakpoint::Line(_)));
+                let breakpoints: Vec<Value> = lines
+                    .iter()
+                    .map(|b| {
+                        let line = b["line"].as_u64().unwrap_or(0) as u32;
+                        match self.debugger.break_at_line(line) {
+                            Ok(line) => json!({
+                                "id": self.added_breakpoint(next_id),
+                                "verified": true,
+                                "line": line,
+                            }),
+                            Err(e) => json!({"verified": false, "line": line, "message": e}),
+                        }
+                    })
+                    .collect();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setFunctionBreakpoints" => {
+                self.retain_breakpoints(|b| !matches!(b, Breakpoint::Function(_)));
+                let names = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                let breakpoints: Vec<Value> = names
+                    .iter()
+                    .map(|b| {
+                        let name = b["name"].as_str().unwrap_or("");
+                        match self.debugger.break_at_function(name) {
+                            Ok(()) => json!({
+                                "id": self.added_breakpoint(next_id),
+                                "verified": true,
+                            }),
+                            Err(e) => json!({"verified": false, "message": e}),
+                        }
+                    })
+                    .collect();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": "main"}]})),
+            "stackTrace" => {
+                let frames: Vec<Value> = self
+                    .debugger
+                    .stack()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, frame)| {
+                        json!({
+                            "id": i,
+                            "name": frame.name,
+                            "line": frame.line.unwrap_or(0),
+                            "column": 1,
+                            "source": {"name": "main.bb", "path": self.path},
+                        })
+                    })
+                    .collect();
+                Ok(json!({"stackFrames": frames, "totalFrames": frames.len()}))
+            }
+            "scopes" => Ok(json!({"scopes": [{
+                "name": "Globals",
+                "variablesReference": GLOBALS,
+                "expensive": false,
+            }]})),
+            "variables" => {
+                let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+                    Some(GLOBALS) => self
+                        .debugger
+                        .variables()
+                        .into_iter()
+                        .map(|(name, value, vt)| {
+                            json!({
+                                "name": name,
+                                "value": value.to_string(),
+                                "type": vt,
+                                "variablesReference": 0,
+                            })
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                Ok(json!({ "variables": variables }))
+            }
+            "continue" | "next" | "stepIn" | "stepOut" | "pause" if !self.started => {
+                Err("The program has not started, send configurationDone".to_string())
+            }
+            "continue" => Ok(json!({"allThreadsContinued": true})),
+            "next" | "stepIn" | "stepOut" | "pause" => Ok(json!({})),
+            _ => Err(format!("Unsupported request '{}'", command)),
+        }
+    }
+
+    /// Whether a `Source` names the project's main.bb. Sources without a
+    /// path are taken to be main.bb, the only file a program has.
+    fn is_main(&self, source: &Value) -> bool {
+        match source["path"].as_str() {
+            Some(path) => {
+                fs::canonicalize(path).is_ok_and(|path| path.display().to_string() == self.path)
+            }
+            None => true,
+        }
+    }
+
+    /// Keeps the breakpoints matching `keep`, along with their ids.
+    fn retain_breakpoints(&mut self, keep: impl Fn(&Breakpoint) -> bool) {
+        let (breakpoints, ids) = self
+            .debugger
+            .breakpoints
+            .drain(..)
+            .zip(self.breakpoint_ids.drain(..))
+            .filter(|(b, _)| keep(b))
+            .unzip();
+        self.debugger.breakpoints = breakpoints;
+        self.breakpoint_ids = ids;
+    }
+
+    /// Assigns the next id to the breakpoint the debugger just added.
+    fn added_breakpoint(&mut self, next_id: &mut u64) -> u64 {
+        let id = *next_id;
+        *next_id += 1;
+        self.breakpoint_ids.push(id);
+        id
+    }
+}
@@ -1,152 +1,475 @@
+use crate::config::{BuildProfile, CompilerConfig};
+use crate::fingerprint::{fingerprint, read_cache, up_to_date, write_cache, CacheEntry};
+use crate::hooks::run_hooks;
+use crate::interp::Interpreter;
+use crate::manifest::{sha256_hex, write_manifest, BuiltArtifact};
+use crate::winres::{build_resources, version_strings};
+use bimble::bytecode::Program;
+use bimble::caps::Capabilities;
+use bimble::check::{check, check_program, CheckedLine, Checker};
+use bimble::codegen::lower;
+use bimble::exe::{add_resource_section, add_section, EmbedMode, SECTION_NAME};
+use bimble::payload::{encode_payload, obfuscate, Codec, Footer, PayloadFormat, KEY_LEN};
+use bimble::preproc::{filter_or_exit, filter_target, TARGETS};
+use bimble::resources::{collect_resources, pack, Resource};
+use bimble::vm::Vm;
 use colored::Colorize;
+use sha2::{Digest, Sha256};
 use std::{
+    env,
     fs::{self, OpenOptions},
     io::{Seek, Write},
     path::Path,
     process::exit,
 };
 
-pub struct CompilerConfig {
-    name: String,
-    authors: String,
-    version: String,
+/// The platform the compiler itself runs on.
+const HOST: &str = if cfg!(windows) { "windows" } else { "linux" };
+
+/// Command-line options that shape a build on top of the config profile.
+pub struct BuildOptions {
+    /// Set by `--legacy-payload`; otherwise each target gets the format its
+    /// VM template reads. bimble no longer ships a VM that reads legacy
+    /// payloads, so `--legacy-payload` needs one of the original templates
+    /// copied next to the compiler as `lb.bjb`/`wb.bjb`.
+    pub payload_format: Option<PayloadFormat>,
+    pub codec: Codec,
+    pub obfuscate: bool,
+    pub embed: EmbedMode,
+    /// Rebuild every target even when its fingerprint is unchanged.
+    pub force: bool,
 }
 
-impl CompilerConfig {
-    pub fn new() -> Self {
-        Self {
-            name: String::new(),
-            authors: String::new(),
-            version: String::new(),
+pub fn compile_project(
+    project_path: String,
+    source_code: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+    options: &BuildOptions,
+) {
+    // Resolve #if blocks and check every target before any artifact is written.
+    // Lowering to bytecode needs the checked program, so only legacy payloads
+    // can skip the checks.
+    let needs_check = profile.checks || options.payload_format != Some(PayloadFormat::Legacy);
+    let resources = match collect_resources(&project_path, &config.resources) {
+        Ok(resources) => resources,
+        Err(err) => {
+            eprintln!("{}{}", "Error: ".red(), err.bold().red());
+            exit(-1);
+        }
+    };
+    if options.payload_format == Some(PayloadFormat::Legacy) {
+        if let Some(feature) = legacy_unsupported(config, options, &resources) {
+            eprintln!(
+                "{}",
+                format!("Error: --legacy-payload builds do not support {}", feature).red()
+            );
+            exit(-1);
         }
     }
-}
-
-#[allow(unused_assignments, unused_variables)]
-pub fn compile_project(project_path: String) {
-    let source_file = format!("{}/{}", project_path, "main.bb");
-    let config_file = format!("{}/{}", project_path, "cfg.bcf");
-    let mut config = CompilerConfig::new();
-
-    // Parse Compiler Configuration
-    match fs::read_to_string(&config_file) {
-        Ok(config_content) => {
-            let config_lines: Vec<&str> = config_content.lines().collect();
-            let mut parsed_config = CompilerConfig::new();
-            for (line_number, line) in config_lines.iter().enumerate() {
-                let parts: Vec<&str> = line.split(':').map(|s| s.trim()).collect();
-
-                if parts.len() > 2 {
-                    eprintln!(
-                        "{}{}{}",
-                        "Error on line ".red(),
-                        format!("{}", line_number + 1).bold().red(),
-                        ": Too many values, expected only a single key-value pair.".red()
-                    );
-                    exit(-1);
-                } else if parts.len() == 2 {
-                    let key = parts[0].to_ascii_uppercase();
-                    let value = parts[1].to_string();
-
-                    match key.as_str() {
-                        "NAME" => parsed_config.name = value,
-                        "AUTHORS" => parsed_config.authors = value,
-                        "VER" => parsed_config.version = value,
-                        _ => {
-                            eprintln!(
-                                "{}{}{}{}{}",
-                                "Error on line ".red(),
-                                format!("{}", line_number + 1).bold().red(),
-                                ": Invalid key '".red(),
-                                key.bold().red(),
-                                "'. Expected 'Name', 'Authors', or 'Ver'.".red()
-                            );
-                            exit(-1);
-                        }
-                    }
-                } else if parts.len() == 1 && !parts[0].is_empty() {
-                    eprintln!(
-                        "{}{}{}{}{}",
-                        "Error on line ".red(),
-                        format!("{}", line_number + 1).bold().red(),
-                        ": Invalid term '".red(),
-                        parts[0].bold().red(),
-                        "'. Expected 'NAME', 'AUTHORS', or 'VERSION'.".red()
-                    );
-                    exit(-1);
-                }
-            }
+    let resource_names: Vec<String> = resources.iter().map(|r| r.name.clone()).collect();
 
-            // Check if any of the required keys are missing
-            if parsed_config.name.is_empty() {
-                eprintln!("{}", "Error: Missing 'NAME' key in config file.".red());
-                exit(-1);
+    let mut programs = Vec::new();
+    for platform in TARGETS {
+        let program = filter_or_exit(source_code, platform);
+        let checked = if needs_check {
+            check(
+                program.clone(),
+                &config.defines,
+                &resource_names,
+                &config.capabilities,
+            )
+        } else {
+            Vec::new()
+        };
+        let legacy = options.payload_format == Some(PayloadFormat::Legacy);
+        let base_vm = match vm_template(platform, legacy) {
+            Ok(base_vm) => base_vm,
+            // A plain `cargo build` only produces the host template
+            Err(err) if platform != HOST => {
+                println!(
+                    "{}",
+                    format!("Warning: {}. Skipping the {} target", err, platform).yellow()
+                );
+                continue;
             }
-            if parsed_config.authors.is_empty() {
-                eprintln!("{}", "Error: Missing 'AUTHORS' key in config file.".red());
+            Err(err) => {
+                eprintln!("{}{}", "Error: ".red(), err.bold().red());
                 exit(-1);
             }
-            if parsed_config.version.is_empty() {
-                eprintln!("{}", "Error: Missing 'VERSION' key in config file.".red());
+        };
+        let payload_format = match payload_format(&base_vm, config, options, &resources) {
+            Ok(format) => format,
+            Err(err) => {
+                eprintln!("{}{}", "Error: ".red(), err.bold().red());
                 exit(-1);
             }
-            config = parsed_config;
+        };
+        programs.push((platform, program, checked, base_vm, payload_format));
+    }
+
+    let build_dir = format!("{}/build", project_path);
+
+    if options.force {
+        if let Err(err) = clean_build_dir(&build_dir) {
+            eprintln!("{}", err);
+            exit(-1);
         }
-        Err(err) => {
-            eprintln!(
-                "{}{}{}{}",
-                "Unable to read config file: ".red(),
-                config_file.bold().red(),
-                ". Please check if it exists and is accessible. Error details: ".red(),
-                err.to_string().bold().red()
+    }
+
+    if Path::new(&build_dir).is_dir() {
+        // Reused for an incremental build
+    } else if let Err(err) = fs::create_dir(&build_dir) {
+        eprintln!(
+            "{}{}{}",
+            "Unable to create build directory in project folder: ".red(),
+            project_path.bold().red(),
+            format!(". Error details: {}", err).red()
+        );
+        exit(-1);
+    }
+
+    // Compile for Linux and Windows platforms, skipping targets whose inputs are unchanged
+    let cache = read_cache(&build_dir);
+    let mut new_cache = Vec::new();
+    let mut artifacts = Vec::new();
+    for (platform, program, checked, base_vm, payload_format) in programs {
+        let fingerprint = fingerprint(
+            platform,
+            source_code,
+            &project_path,
+            config,
+            profile,
+            options,
+            &resources,
+            &base_vm,
+        );
+        if let Some(entry) = up_to_date(&cache, &build_dir, platform, &fingerprint) {
+            println!(
+                "{}{}{}",
+                "Target ".blue(),
+                platform.bold().cyan(),
+                " is up to date".blue()
             );
+            artifacts.push(BuiltArtifact {
+                platform,
+                base_vm,
+                payload_format,
+                path: format!("{}/{}", build_dir, entry.artifact),
+            });
+            new_cache.push(CacheEntry {
+                platform: entry.platform.clone(),
+                fingerprint,
+                artifact: entry.artifact.clone(),
+                sha256: entry.sha256.clone(),
+            });
+            continue;
+        }
+
+        if let Err(err) = clean_build_dir(&format!("{}/{}", build_dir, platform)) {
+            eprintln!("{}", err);
             exit(-1);
         }
-    }
 
-    match fs::read_to_string(&source_file) {
-        Ok(source_code) => {
-            let bytecode = source_code.as_bytes().to_vec();
-            let mut binary_code = String::new();
-            for byte in bytecode {
-                binary_code.push_str(&format!("{:08b}", byte).trim());
-                binary_code.push('.');
+        let binary_code = match payload_format {
+            PayloadFormat::Legacy => {
+                let payload = apply_profile(&program, &config.define_lines(), profile);
+                encode_payload(payload.as_bytes(), PayloadFormat::Legacy)
             }
-            let linux_vm = "./lb.bjb";
-            let windows_vm = "./wb.bjb";
-            let build_dir = format!("{}/build", project_path);
-
-            if let Err(err) = clean_build_dir(&build_dir) {
-                eprintln!("{}", err);
-                exit(-1);
+            PayloadFormat::Bytecode => {
+                let mut bytecode = lower_or_exit(&checked, &program, profile);
+                bytecode.limits = config.limits;
+                bytecode.capabilities = config.capabilities.clone();
+                let bytecode = bytecode.to_bytes();
+                let mut payload = encode_payload(&bytecode, PayloadFormat::Bytecode);
+                if !resources.is_empty() {
+                    payload.extend(pack(&resources));
+                }
+                let stored = options.codec.compress(&payload);
+                if options.obfuscate {
+                    let key = build_key(&stored);
+                    let mut sealed = key.to_vec();
+                    sealed.extend(obfuscate(&stored, &key));
+                    sealed
+                } else {
+                    stored
+                }
             }
-
-            if let Err(err) = fs::create_dir(&build_dir) {
-                eprintln!(
-                    "{}{}{}",
-                    "Unable to create build directory in project folder: ".red(),
-                    project_path.bold().red(),
-                    format!(". Error details: {}", err).red()
-                );
+        };
+        let vm = match prepare_vm(platform, &project_path, config, &base_vm) {
+            Ok(vm) => vm,
+            Err(err) => {
+                eprintln!("{}{}", "Error: ".red(), err.bold().red());
                 exit(-1);
             }
-
-            // Compile for Linux and Windows platforms
-            compile_binary("linux", &build_dir, &config, &binary_code, linux_vm);
-            compile_binary("windows", &build_dir, &config, &binary_code, windows_vm);
+        };
+        if let Some(artifact) = compile_binary(
+            platform,
+            &build_dir,
+            config,
+            &binary_code,
+            payload_format,
+            &base_vm,
+            &vm,
+            options,
+        ) {
+            artifacts.push(BuiltArtifact {
+                platform,
+                base_vm,
+                payload_format,
+                path: artifact.clone(),
+            });
+            if let Ok(data) = fs::read(&artifact) {
+                new_cache.push(CacheEntry {
+                    platform: platform.to_string(),
+                    fingerprint,
+                    artifact: format!("{}/{}", platform, config.artifact_name(platform)),
+                    sha256: sha256_hex(&data),
+                });
+            }
+            let mut env = hook_env(&project_path, config, profile);
+            env.push(("BIMBLE_TARGET", platform.to_string()));
+            // Hooks run from the project folder, so hand them an absolute path
+            let artifact = fs::canonicalize(&artifact)
+                .map(|p| p.display().to_string())
+                .unwrap_or(artifact);
+            env.push(("BIMBLE_ARTIFACT", artifact));
+            run_hooks("post-build", &config.post_build, &project_path, &env);
         }
-        Err(err) => {
-            eprintln!(
-                "{}{}{}",
-                "Unable to read source file: ".red(),
-                source_file.bold().red(),
-                format!(". Error details: {}", err).red()
-            );
+    }
+
+    if let Err(err) = write_cache(&build_dir, &new_cache) {
+        eprintln!("{}{}", "Error: ".red(), err.bold().red());
+        exit(-1);
+    }
+
+    if let Err(err) = write_manifest(
+        &project_path,
+        source_code,
+        config,
+        profile,
+        options,
+        &resources,
+        &artifacts,
+    ) {
+        eprintln!("{}{}", "Error: ".red(), err.bold().red());
+        exit(-1);
+    }
+    println!(
+        "{}{}",
+        "Wrote build manifest -> ".blue(),
+        format!("{}/manifest.json", build_dir).cyan()
+    );
+}
+
+/// A program checked for the host platform, ready to run in-process.
+pub struct HostProgram {
+    /// main.bb after `#if` resolution, one entry per source line.
+    pub source: Vec<String>,
+    pub checked: Vec<CheckedLine>,
+    pub resources: Vec<Resource>,
+    /// The config's capabilities with relative paths resolved against the
+    /// project folder.
+    pub capabilities: Capabilities,
+}
+
+/// Like `host_program`, but reports the error and exits.
+pub fn load_host_program(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+) -> HostProgram {
+    match host_program(project_path, source_code, config) {
+        Ok(host) => host,
+        Err(e) => {
+            eprintln!("{}{}", "Error: ".red(), e.bold().red());
             exit(-1);
         }
     }
 }
 
+/// Resolves `#if` blocks for the host platform, collects resources and checks
+/// the program.
+pub fn host_program(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+) -> Result<HostProgram, String> {
+    let resources = collect_resources(project_path, &config.resources)?;
+    let resource_names: Vec<String> = resources.iter().map(|r| r.name.clone()).collect();
+    let source = filter_target(source_code, HOST)
+        .map_err(|e| format!("{} at code ({}): {}", e.msg, e.index, e.line.trim()))?;
+    let mut checker = Checker::new(&resource_names, &config.capabilities);
+    let checked = check_program(&mut checker, &source, &config.defines)?;
+    Ok(HostProgram {
+        source,
+        checked,
+        resources,
+        capabilities: config.capabilities.relative_to(Path::new(project_path)),
+    })
+}
+
+/// Runs main.bb for the host platform without writing an artifact, either on
+/// the bytecode VM or, with `interp`, on the tree-walking interpreter.
+pub fn run_project(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+    interp: bool,
+) {
+    let host = load_host_program(project_path, source_code, config);
+
+    if interp {
+        let mut interpreter = Interpreter::new(host.resources, host.capabilities)
+            .with_limits(config.limits);
+        if let Err(e) = interpreter.run(&host.checked) {
+            report_code_error(e.index, &e.msg, &host.source);
+            exit(e.exit_code());
+        }
+    } else {
+        let mut bytecode = lower_or_exit(&host.checked, &host.source, profile);
+        bytecode.limits = config.limits;
+        bytecode.capabilities = host.capabilities;
+        if let Err(err) = Vm::new(bytecode, host.resources).run() {
+            // main.bb is on disk, so quote its lines in the trace
+            let err = err.quote(&host.source);
+            eprintln!("{}{}", "Error: ".red(), err.to_string().bold().red());
+            exit(err.exit_code());
+        }
+    }
+}
+
+/// Derives the obfuscation key from the payload itself, so that identical
+/// inputs keep producing byte-identical artifacts while different programs
+/// still get different keys.
+fn build_key(payload: &[u8]) -> [u8; KEY_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"bimble-obfuscation-key");
+    hasher.update(payload);
+    hasher.finalize()[..KEY_LEN].try_into().unwrap()
+}
+
+pub fn lower_or_exit(checked: &[CheckedLine], program: &[String], profile: &BuildProfile) -> Program {
+    match lower(checked, profile.line_table) {
+        Ok(bytecode) => bytecode,
+        Err(e) => code_error(e.index, &e.msg, program),
+    }
+}
+
+/// Like `lower_or_exit`, but returns the error as `msg at code (N): line`.
+pub fn lower_program(
+    checked: &[CheckedLine],
+    program: &[String],
+    profile: &BuildProfile,
+) -> Result<Program, String> {
+    lower(checked, profile.line_table).map_err(|e| {
+        let line = program.get(e.index.wrapping_sub(1)).map_or("", |l| l.trim());
+        format!("{} at code ({}): {}", e.msg, e.index, line)
+    })
+}
+
+/// Reports an error on line `index` of main.bb (0 for defines) and exits.
+pub fn code_error(index: usize, msg: &str, program: &[String]) -> ! {
+    report_code_error(index, msg, program);
+    exit(-1);
+}
+
+fn report_code_error(index: usize, msg: &str, program: &[String]) {
+    let line = match index {
+        0 => "",
+        i => program[i - 1].trim(),
+    };
+    eprintln!(
+        "{}{}{}{}{}",
+        "Error: ".red(),
+        msg.red(),
+        " at code (".red(),
+        index.to_string().bold().red(),
+        format!("): {}", line).red().bold()
+    );
+}
+
+/// Rewrites the program text according to the build profile. Defines are
+/// emitted ahead of the program and recorded as line 0 in the line table, a
+/// trailing `#@lines` comment mapping every emitted line back to main.bb.
+fn apply_profile(program: &[String], defines: &[String], profile: &BuildProfile) -> String {
+    let mut lines = defines.to_vec();
+    let mut line_table = vec!["0".to_string(); defines.len()];
+
+    for (index, line) in program.iter().enumerate() {
+        let cd = line.trim();
+        if profile.strip_comments && cd.starts_with('#') {
+            continue;
+        }
+        if profile.strip_blank_lines && cd.is_empty() {
+            continue;
+        }
+        if profile.minify {
+            lines.push(minify_line(cd));
+        } else {
+            lines.push(line.to_string());
+        }
+        line_table.push((index + 1).to_string());
+    }
+
+    if profile.line_table {
+        lines.push(format!("#@lines {}", line_table.join(",")));
+    }
+
+    lines.join("\n")
+}
+
+/// Collapses whitespace runs outside of string literals into a single space.
+fn minify_line(line: &str) -> String {
+    let mut out = String::new();
+    let mut quote_char = None;
+    let mut in_space = false;
+
+    for c in line.chars() {
+        match quote_char {
+            Some(q) => {
+                out.push(c);
+                if c == q {
+                    quote_char = None;
+                }
+            }
+            None if c.is_whitespace() => in_space = true,
+            None => {
+                if in_space && !out.is_empty() {
+                    out.push(' ');
+                }
+                in_space = false;
+                if c == '"' || c == '\'' {
+                    quote_char = Some(c);
+                }
+                out.push(c);
+            }
+        }
+    }
+
+    out
+}
+
+/// Environment variables handed to every build hook.
+pub fn hook_env(
+    project_path: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "BIMBLE_PROJECT",
+            fs::canonicalize(project_path)
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| project_path.to_string()),
+        ),
+        ("BIMBLE_NAME", config.name.clone()),
+        ("BIMBLE_VERSION", config.version.clone()),
+        ("BIMBLE_PROFILE", profile.name.to_string()),
+    ]
+}
+
 fn clean_build_dir(build_dir: &str) -> Result<(), String> {
     if Path::new(build_dir).exists() {
         match fs::metadata(build_dir) {
@@ -181,19 +504,166 @@ fn clean_build_dir(build_dir: &str) -> Result<(), String> {
     Ok(())
 }
 
+/// Picks the `bimble-vm` template for `platform`: the one cargo built next to
+/// the compiler for the host platform, then one cross-built with
+/// `cargo build --target <triple>` into the same target directory, then the
+/// `lb.bjb`/`wb.bjb` copies build.sh installs next to the compiler. A
+/// `legacy` build only looks for `lb.bjb`/`wb.bjb`, where an original VM
+/// template has to be supplied by the user.
+fn vm_template(platform: &str, legacy: bool) -> Result<String, String> {
+    let (template, vm, triple) = if platform == "linux" {
+        ("lb.bjb", "bimble-vm", "x86_64-unknown-linux-musl")
+    } else {
+        ("wb.bjb", "bimble-vm.exe", "x86_64-pc-windows-gnu")
+    };
+    let exe = env::current_exe().map_err(|e| format!("Unable to locate the compiler: {}", e))?;
+    let dir = exe.parent().unwrap_or(Path::new("."));
+
+    if legacy {
+        let path = dir.join(template);
+        return match path.is_file() {
+            true => Ok(path.display().to_string()),
+            false => Err(format!(
+                "--legacy-payload needs an original VM template for {} at {}, which bimble no longer ships. Copy one there, or drop --legacy-payload to use the bimble-vm template from build.sh or `cargo build --bin bimble-vm`",
+                platform,
+                path.display()
+            )),
+        };
+    }
+
+    let mut candidates = Vec::new();
+    if platform == HOST {
+        candidates.push(dir.join(vm));
+    }
+    // The compiler sits in target/<profile> or target/<host triple>/<profile>
+    if let (Some(profile), Some(parent)) = (dir.file_name(), dir.parent()) {
+        for target_dir in [Some(parent), parent.parent()].into_iter().flatten() {
+            candidates.push(target_dir.join(triple).join(profile).join(vm));
+        }
+    }
+    candidates.push(dir.join(template));
+
+    candidates
+        .into_iter()
+        .find(|c| c.is_file())
+        .map(|c| c.display().to_string())
+        .ok_or_else(|| {
+            format!(
+                "No bimble-vm template for {}. Build one with `cargo build --bin bimble-vm --target {}` or run build.sh",
+                platform, triple
+            )
+        })
+}
+
+/// What a program needs that legacy payloads cannot carry, if anything.
+fn legacy_unsupported(
+    config: &CompilerConfig,
+    options: &BuildOptions,
+    resources: &[Resource],
+) -> Option<&'static str> {
+    if options.codec != Codec::None {
+        Some("compression")
+    } else if options.obfuscate {
+        Some("obfuscation")
+    } else if options.embed == EmbedMode::Section {
+        Some("section embedding")
+    } else if !resources.is_empty() {
+        Some("resources")
+    } else if !config.limits.is_unlimited() {
+        Some("runtime limits")
+    } else if config.capabilities != Capabilities::default() {
+        Some("capabilities")
+    } else {
+        None
+    }
+}
+
+/// Picks the payload format for `base_vm`, which only reads one: bytecode for a
+/// `bimble-vm` template, legacy for the original ones.
+fn payload_format(
+    base_vm: &str,
+    config: &CompilerConfig,
+    options: &BuildOptions,
+    resources: &[Resource],
+) -> Result<PayloadFormat, String> {
+    let vm = fs::read(base_vm).map_err(|e| {
+        format!(
+            "Unable to read base virtual machine (VM) {}: {}",
+            base_vm, e
+        )
+    })?;
+    match (PayloadFormat::read_by(&vm), options.payload_format) {
+        (PayloadFormat::Bytecode, Some(PayloadFormat::Legacy)) => Err(format!(
+            "--legacy-payload needs one of the original VM templates, but {} is a bimble-vm. Replace it with an original template or drop --legacy-payload",
+            base_vm
+        )),
+        (PayloadFormat::Bytecode, _) => Ok(PayloadFormat::Bytecode),
+        (PayloadFormat::Legacy, requested) => {
+            if let Some(feature) = legacy_unsupported(config, options, resources) {
+                return Err(format!(
+                    "VM template {} only reads legacy payloads, which do not support {}. Rebuild it from bimble-vm",
+                    base_vm, feature
+                ));
+            }
+            if requested.is_none() {
+                println!(
+                    "{}",
+                    format!(
+                        "Note: VM template {} only reads legacy payloads; embedding the source as a legacy payload",
+                        base_vm
+                    )
+                    .yellow()
+                );
+            }
+            Ok(PayloadFormat::Legacy)
+        }
+    }
+}
+
+/// Reads the VM template for `platform`. Windows templates get a VERSIONINFO
+/// resource built from cfg.bcf and the optional `Icon`.
+fn prepare_vm(
+    platform: &str,
+    project_path: &str,
+    config: &CompilerConfig,
+    base_vm: &str,
+) -> Result<Vec<u8>, String> {
+    let vm = fs::read(base_vm).map_err(|e| {
+        format!(
+            "Unable to read base virtual machine (VM) {}: {}",
+            base_vm, e
+        )
+    })?;
+    if platform != "windows" {
+        return Ok(vm);
+    }
+
+    let icon = match &config.target(platform).icon {
+        Some(icon) => Some(
+            fs::read(Path::new(project_path).join(icon))
+                .map_err(|e| format!("Unable to read icon {}: {}", icon, e))?,
+        ),
+        None => None,
+    };
+    let strings = version_strings(config);
+    add_resource_section(&vm, |rva| {
+        build_resources(&strings, &config.version, icon.as_deref(), rva)
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 fn compile_binary(
     platform: &str,
     build_dir: &str,
     config: &CompilerConfig,
-    binary_code: &str,
+    binary_code: &[u8],
+    payload_format: PayloadFormat,
     base_vm: &str,
-) {
+    vm: &[u8],
+    options: &BuildOptions,
+) -> Option<String> {
     let platform_dir = format!("{}/{}", build_dir, platform);
-    let output_file = if platform == "linux" {
-        format!("{}/{}", platform_dir, config.name)
-    } else {
-        format!("{}/{}.exe", platform_dir, config.name)
-    };
+    let output_file = format!("{}/{}", platform_dir, config.artifact_name(platform));
 
     if let Err(err) = fs::create_dir(&platform_dir) {
         eprintln!(
@@ -202,18 +672,56 @@ fn compile_binary(
             platform_dir.bold().red(),
             format!(". Error details: {}", err).red()
         );
-        return;
+        return None;
     }
 
-    match fs::copy(base_vm, &output_file) {
+    if options.embed == EmbedMode::Section {
+        // The footer sits at the end of the section and points at its start
+        let mut contents = binary_code.to_vec();
+        contents.extend(Footer::new(0, binary_code, options.codec, options.obfuscate).to_bytes());
+        let written = add_section(vm, SECTION_NAME, &contents).and_then(|exe| {
+            // Copy first so the artifact keeps the template's permissions
+            fs::copy(base_vm, &output_file)
+                .and_then(|_| fs::write(&output_file, exe))
+                .map_err(|e| format!("Unable to write {}: {}", output_file, e))
+        });
+        if let Err(err) = written {
+            eprintln!(
+                "{}{}",
+                format!("Unable to embed payload in the {} executable: ", platform).red(),
+                err.bold().red()
+            );
+            return None;
+        }
+        println!(
+            "{}{}{}{}",
+            "Successfully compiled for ".blue(),
+            platform.bold().cyan(),
+            " at -> ".blue(),
+            platform_dir.cyan()
+        );
+        return Some(output_file);
+    }
+
+    // Copy first so the artifact keeps the template's permissions
+    match fs::copy(base_vm, &output_file).and_then(|_| fs::write(&output_file, vm)) {
         Ok(_) => {
-            match OpenOptions::new()
-                .append(true)
-                .write(true)
-                .open(&output_file)
-            {
+            match OpenOptions::new().append(true).open(&output_file) {
                 Ok(mut exe) => {
-                    let code_length = binary_code.chars().count();
+                    let code_length = binary_code.len();
+                    let payload_offset = match exe.metadata() {
+                        Ok(metadata) => metadata.len(),
+                        Err(err) => {
+                            eprintln!(
+                                "{}{}{}",
+                                format!("Unable to read size of the {} executable: ", platform)
+                                    .red(),
+                                output_file.bold().red(),
+                                format!(". Error details: {}", err).red()
+                            );
+                            return None;
+                        }
+                    };
 
                     // Append the binary code to the executable
                     if let Err(err) = exe.seek(std::io::SeekFrom::End(0)) {
@@ -224,10 +732,10 @@ fn compile_binary(
                             output_file.bold().red(),
                             format!(". Error details: {}", err).red()
                         );
-                        return;
+                        return None;
                     }
 
-                    if let Err(err) = exe.write_all(binary_code.as_bytes()) {
+                    if let Err(err) = exe.write_all(binary_code) {
                         eprintln!(
                             "{}{}{}",
                             format!("Unable to write binary code to {} executable: ", platform)
@@ -235,45 +743,66 @@ fn compile_binary(
                             output_file.bold().red(),
                             format!(". Error details: {}", err).red()
                         );
-                        return;
+                        return None;
                     }
 
-                    // Calculate the length of the appended data
-                    let length_str = format!("{}", code_length);
-                    let length_padding = 10;
-                    let mut length_data = vec![b' '; length_padding];
+                    if payload_format == PayloadFormat::Legacy {
+                        // Calculate the length of the appended data
+                        let length_str = format!("{}", code_length);
+                        let length_padding = 10;
+                        let mut length_data = vec![b' '; length_padding];
 
-                    // Pad the length string
-                    let length_pos = length_padding - length_str.len();
-                    length_data[length_pos..].copy_from_slice(length_str.as_bytes());
+                        // Pad the length string
+                        let length_pos = length_padding - length_str.len();
+                        length_data[length_pos..].copy_from_slice(length_str.as_bytes());
 
-                    // Write the length at the end of the file
-                    if let Err(err) = exe.seek(std::io::SeekFrom::End(-(length_padding as i64))) {
-                        eprintln!(
-                            "{}{}{}",
-                            format!(
-                                "Unable to seek to length padding position in {} executable: ",
-                                platform
-                            )
-                            .red(),
-                            output_file.bold().red(),
-                            format!(". Error details: {}", err).red()
-                        );
-                        return;
-                    }
+                        // Write the length at the end of the file
+                        if let Err(err) = exe.seek(std::io::SeekFrom::End(-(length_padding as i64)))
+                        {
+                            eprintln!(
+                                "{}{}{}",
+                                format!(
+                                    "Unable to seek to length padding position in {} executable: ",
+                                    platform
+                                )
+                                .red(),
+                                output_file.bold().red(),
+                                format!(". Error details: {}", err).red()
+                            );
+                            return None;
+                        }
 
-                    if let Err(err) = exe.write_all(&length_data) {
-                        eprintln!(
-                            "{}{}{}",
-                            format!(
-                                "Unable to write length padding to {} executable: ",
-                                platform
-                            )
-                            .red(),
-                            output_file.bold().red(),
-                            format!(". Error details: {}", err).red()
+                        if let Err(err) = exe.write_all(&length_data) {
+                            eprintln!(
+                                "{}{}{}",
+                                format!(
+                                    "Unable to write length padding to {} executable: ",
+                                    platform
+                                )
+                                .red(),
+                                output_file.bold().red(),
+                                format!(". Error details: {}", err).red()
+                            );
+                            return None;
+                        }
+                    } else {
+                        // Self-describing footer: offset, length and CRC32 of the payload
+                        let footer = Footer::new(
+                            payload_offset,
+                            binary_code,
+                            options.codec,
+                            options.obfuscate,
                         );
-                        return;
+                        if let Err(err) = exe.write_all(&footer.to_bytes()) {
+                            eprintln!(
+                                "{}{}{}",
+                                format!("Unable to write footer to {} executable: ", platform)
+                                    .red(),
+                                output_file.bold().red(),
+                                format!(". Error details: {}", err).red()
+                            );
+                            return None;
+                        }
                     }
 
                     println!(
@@ -283,6 +812,7 @@ fn compile_binary(
                         " at -> ".blue(),
                         platform_dir.cyan()
                     );
+                    Some(output_file)
                 }
                 Err(err) => {
                     eprintln!(
@@ -291,6 +821,7 @@ fn compile_binary(
                         output_file.bold().red(),
                         format!(". Error details: {}", err).red()
                     );
+                    None
                 }
             }
         }
@@ -305,6 +836,51 @@ fn compile_binary(
                 output_file.bold().red(),
                 format!(". Error details: {}", err).red()
             );
+            None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn profiles_shape_the_program() {
+        let program = lines("# greet\n\nmay  x =   5\n    echoln(x)");
+        let defines = ["may N = 1".to_string()];
+
+        assert_eq!(
+            apply_profile(&program, &defines, &BuildProfile::dev()),
+            "may N = 1\n# greet\n\nmay  x =   5\n    echoln(x)\n#@lines 0,1,2,3,4"
+        );
+        assert_eq!(
+            apply_profile(&program, &defines, &BuildProfile::release()),
+            "may N = 1\nmay x = 5\necholn(x)"
+        );
+
+        // A release build that keeps its line table maps the stripped lines
+        let profile = BuildProfile {
+            line_table: true,
+            ..BuildProfile::release()
+        };
+        assert_eq!(
+            apply_profile(&program, &[], &profile),
+            "may x = 5\necholn(x)\n#@lines 3,4"
+        );
+    }
+
+    #[test]
+    fn minifying_keeps_string_literals() {
+        assert_eq!(
+            minify_line("echoln( \"a  # b\" ,   'c   d' )"),
+            "echoln( \"a  # b\" , 'c   d' )"
+        );
+        assert_eq!(minify_line("may\tmsg =  \" \\t \""), "may msg = \" \\t \"");
+        assert_eq!(minify_line("   "), "");
+    }
+}
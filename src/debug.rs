@@ -0,0 +1,528 @@
+use crate::config::{BuildProfile, CompilerConfig};
+use crate::datagen::{host_program, lower_program};
+use bimble::bytecode::Value;
+use bimble::check::{CheckedLine, Stmt, VT};
+use bimble::vm::{StackFrame, Status, Vm};
+use colored::Colorize;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::process::exit;
+use std::time::Instant;
+
+pub enum Breakpoint {
+    /// Stop before the statement on this main.bb line.
+    Line(u32),
+    /// Stop on entry to this ON function.
+    Function(String),
+}
+
+/// How far `resume` runs before stopping again.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Resume {
+    /// To the next statement, entering calls.
+    Step,
+    /// To the next statement in this function or its caller.
+    Next,
+    /// Out of the current function.
+    Out,
+    /// To the next breakpoint.
+    Continue,
+}
+
+/// Why execution stopped.
+pub enum Stop {
+    /// Before the statement on `line`; `breakpoint` is the index of the
+    /// breakpoint that was hit, if any.
+    Paused {
+        line: u32,
+        breakpoint: Option<usize>,
+    },
+    Exited,
+    /// A runtime error ended the program.
+    Failed {
+        line: Option<u32>,
+        msg: String,
+    },
+}
+
+/// Runs a program on the VM one statement at a time. Front ends (the
+/// `bimble debug` prompt and the DAP server) drive it with `resume`.
+pub struct Debugger {
+    pub vm: Vm,
+    pub breakpoints: Vec<Breakpoint>,
+    /// VT of every variable, from its `may` declaration.
+    types: HashMap<String, VT>,
+    /// When the program last paused; time paused does not count towards the
+    /// time limit.
+    paused_at: Option<Instant>,
+}
+
+impl Debugger {
+    pub fn new(vm: Vm, checked: &[CheckedLine]) -> Self {
+        let types = checked
+            .iter()
+            .filter_map(|line| match &line.stmt {
+                Stmt::Let { name, vt, .. } => Some((name.clone(), vt.clone())),
+                _ => None,
+            })
+            .collect();
+        Self {
+            vm,
+            breakpoints: Vec::new(),
+            types,
+            paused_at: None,
+        }
+    }
+
+    /// Resolves a line breakpoint to the first line at or after `line` that
+    /// has code.
+    pub fn break_at_line(&mut self, line: u32) -> Result<u32, String> {
+        let resolved = self
+            .vm
+            .program
+            .lines
+            .iter()
+            .map(|(_, l)| *l)
+            .filter(|l| *l >= line && *l > 0)
+            .min()
+            .ok_or_else(|| format!("No code at or after line {}", line))?;
+        self.breakpoints.push(Breakpoint::Line(resolved));
+        Ok(resolved)
+    }
+
+    pub fn break_at_function(&mut self, name: &str) -> Result<(), String> {
+        if !self.vm.program.fns.iter().any(|f| f.name == name) {
+            return Err(format!("No function named '{}'", name));
+        }
+        self.breakpoints
+            .push(Breakpoint::Function(name.to_string()));
+        Ok(())
+    }
+
+    /// Stops before the first statement of main.bb; defines run silently.
+    pub fn start(&mut self) -> Stop {
+        if let Some(line) = self.statement_line() {
+            return Stop::Paused {
+                line,
+                breakpoint: self.breakpoint_hit(line),
+            };
+        }
+        self.resume(Resume::Step)
+    }
+
+    pub fn resume(&mut self, mode: Resume) -> Stop {
+        let depth = self.vm.frames.len();
+        loop {
+            if let Some(stop) = self.advance(mode, depth, usize::MAX) {
+                return stop;
+            }
+        }
+    }
+
+    /// Runs at most `steps` instructions of a `resume(mode)` that started at
+    /// call depth `depth`; `None` if it has not stopped yet.
+    pub fn advance(&mut self, mode: Resume, depth: usize, steps: usize) -> Option<Stop> {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.vm.exclude_time(paused_at.elapsed());
+        }
+        for _ in 0..steps {
+            match self.vm.step() {
+                Ok(Status::Running) => (),
+                Ok(Status::Halted) => return Some(Stop::Exited),
+                Err(msg) => {
+                    // The failing instruction is the one before pc
+                    let line = self.vm.program.line_of(self.vm.pc.saturating_sub(1));
+                    return Some(Stop::Failed { line, msg });
+                }
+            }
+            let Some(line) = self.statement_line() else {
+                continue;
+            };
+            let breakpoint = self.breakpoint_hit(line);
+            let depth_now = self.vm.frames.len();
+            let stop = breakpoint.is_some()
+                || match mode {
+                    Resume::Step => true,
+                    Resume::Next => depth_now <= depth,
+                    Resume::Out => depth_now < depth,
+                    Resume::Continue => false,
+                };
+            if stop {
+                self.paused_at = Some(Instant::now());
+                return Some(Stop::Paused { line, breakpoint });
+            }
+        }
+        None
+    }
+
+    /// The main.bb line of the statement about to run, if `pc` is at the
+    /// start of one.
+    fn statement_line(&self) -> Option<u32> {
+        self.vm
+            .program
+            .line_starting_at(self.vm.pc)
+            .filter(|line| *line > 0)
+    }
+
+    fn breakpoint_hit(&self, line: u32) -> Option<usize> {
+        let function = self.vm.frames.last().map(|f| f.function);
+        self.breakpoints.iter().position(|b| match b {
+            Breakpoint::Line(l) => *l == line,
+            Breakpoint::Function(name) => function.is_some_and(|f| {
+                self.vm.program.fns[f].name == *name
+                    && self.vm.program.fns[f].entry as usize == self.vm.pc
+            }),
+        })
+    }
+
+    pub fn stack(&self) -> Vec<StackFrame> {
+        self.vm.backtrace(self.vm.pc)
+    }
+
+    /// Assigned variables with their values and VT types, sorted by name.
+    pub fn variables(&self) -> Vec<(String, Value, String)> {
+        let mut vars: Vec<_> = self
+            .vm
+            .program
+            .vars
+            .iter()
+            .zip(&self.vm.vars)
+            .filter_map(|(name, value)| {
+                let value = value.clone()?;
+                Some((name.clone(), value.clone(), self.type_name(name, &value)))
+            })
+            .collect();
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+        vars
+    }
+
+    fn type_name(&self, name: &str, value: &Value) -> String {
+        match self.types.get(name) {
+            Some(vt) => format!("{:?}", vt),
+            None => match value {
+                Value::Str(_) => "Str",
+                Value::Int(_) => "Int",
+                Value::Float(_) => "Float",
+            }
+            .to_string(),
+        }
+    }
+}
+
+/// Checks and lowers main.bb for the host platform and wraps it in a
+/// `Debugger` whose program writes to `out`, returning the `#if`-resolved
+/// source lines alongside.
+pub fn load_debugger(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+    out: Box<dyn Write>,
+) -> Result<(Debugger, Vec<String>), String> {
+    let host = host_program(project_path, source_code, config)?;
+    // Stepping needs the line table whatever the profile says
+    let profile = BuildProfile {
+        line_table: true,
+        ..profile.clone()
+    };
+    let mut program = lower_program(&host.checked, &host.source, &profile)?;
+    program.limits = config.limits;
+    program.capabilities = host.capabilities;
+    let vm = Vm::new(program, host.resources).with_output(out);
+    Ok((Debugger::new(vm, &host.checked), host.source))
+}
+
+/// Runs `bimble debug`: an interactive prompt over a `Debugger`.
+pub fn debug_project(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+) {
+    let (mut debugger, source) = match load_debugger(
+        project_path,
+        source_code,
+        config,
+        profile,
+        Box::new(io::stdout()),
+    ) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}{}", "Error: ".red(), e.bold().red());
+            exit(-1);
+        }
+    };
+
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("{}{}", "Error: ".bold().red(), e.to_string().bold().red());
+            return;
+        }
+    };
+    println!("{}", "bimble debug - type help for commands".blue());
+
+    let mut stop = debugger.start();
+    loop {
+        let current = match &stop {
+            Stop::Paused { line, breakpoint } => {
+                if let Some(b) = breakpoint {
+                    println!("{}", format!("Breakpoint {} hit", b + 1).yellow());
+                }
+                show_line(&source, *line);
+                Some(*line)
+            }
+            Stop::Exited => {
+                println!("{}", "Program exited".blue());
+                None
+            }
+            Stop::Failed { line, msg } => {
+                let text = line
+                    .and_then(|l| source.get((l as usize).wrapping_sub(1)))
+                    .map_or("", |l| l.trim());
+                eprintln!(
+                    "{}{}{}{}{}",
+                    "Error: ".red(),
+                    msg.red(),
+                    " at code (".red(),
+                    line.unwrap_or(0).to_string().bold().red(),
+                    format!("): {}", text).red().bold()
+                );
+                None
+            }
+        };
+        if current.is_none() {
+            break;
+        }
+
+        // Read commands until one resumes execution
+        let mode = loop {
+            let input = match editor.readline("(bbdb) ") {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(_) => return,
+            };
+            if !input.trim().is_empty() {
+                let _ = editor.add_history_entry(input.as_str());
+            }
+            let mut words = input.split_whitespace();
+            let arg = |words: &mut std::str::SplitWhitespace| words.next().map(str::to_string);
+            match words.next().unwrap_or("") {
+                "s" | "step" => break Resume::Step,
+                "n" | "next" => break Resume::Next,
+                "o" | "out" => break Resume::Out,
+                "c" | "continue" => break Resume::Continue,
+                "b" | "break" => match arg(&mut words) {
+                    Some(target) => match target.parse::<u32>() {
+                        Ok(line) => match debugger.break_at_line(line) {
+                            Ok(line) => println!(
+                                "Breakpoint {} at line {}",
+                                debugger.breakpoints.len(),
+                                line
+                            ),
+                            Err(e) => report(&e),
+                        },
+                        Err(_) => match debugger.break_at_function(&target) {
+                            Ok(()) => println!(
+                                "Breakpoint {} at function {}",
+                                debugger.breakpoints.len(),
+                                target
+                            ),
+                            Err(e) => report(&e),
+                        },
+                    },
+                    None => report("Usage: break <line|function>"),
+                },
+                "d" | "delete" => match arg(&mut words).and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) if n >= 1 && n <= debugger.breakpoints.len() => {
+                        debugger.breakpoints.remove(n - 1);
+                    }
+                    _ => report("Usage: delete <breakpoint number>"),
+                },
+                "breaks" => {
+                    for (i, b) in debugger.breakpoints.iter().enumerate() {
+                        match b {
+                            Breakpoint::Line(line) => println!("  {} line {}", i + 1, line),
+                            Breakpoint::Function(name) => println!("  {} function {}", i + 1, name),
+                        }
+                    }
+                }
+                "bt" | "stack" => {
+                    for (i, frame) in debugger.stack().iter().enumerate() {
+                        println!(
+                            "  #{} {} at line {}",
+                            i,
+                            frame.name.cyan(),
+                            frame.line.unwrap_or(0)
+                        );
+                    }
+                }
+                "p" | "print" => match arg(&mut words) {
+                    Some(name) => match debugger.variables().into_iter().find(|v| v.0 == name) {
+                        Some((name, value, vt)) => {
+                            println!("  {} = {} ({})", name.cyan(), value, vt)
+                        }
+                        None => report(&format!("No variable named '{}' is assigned", name)),
+                    },
+                    None => report("Usage: print <variable>"),
+                },
+                "vars" => {
+                    for (name, value, vt) in debugger.variables() {
+                        println!("  {} = {} ({})", name.cyan(), value, vt);
+                    }
+                }
+                "l" | "list" => {
+                    let line = current.unwrap_or(1) as usize;
+                    for n in line.saturating_sub(3).max(1)..=line + 3 {
+                        if let Some(text) = source.get(n - 1) {
+                            let marker = if n == line { "=>" } else { "  " };
+                            println!("{} {:>4} {}", marker, n, text);
+                        }
+                    }
+                }
+                "q" | "quit" => return,
+                "h" | "help" => help(),
+                "" => (),
+                other => report(&format!("Unknown command '{}', type help", other)),
+            }
+        };
+        stop = debugger.resume(mode);
+    }
+}
+
+fn show_line(source: &[String], line: u32) {
+    let text = source
+        .get((line as usize).wrapping_sub(1))
+        .map_or("", |l| l.trim());
+    println!("{} {}", format!("line {}:", line).cyan(), text);
+}
+
+fn help() {
+    println!("  s, step            run to the next statement, entering calls");
+    println!("  n, next            run to the next statement in this function");
+    println!("  o, out             run until the current function returns");
+    println!("  c, continue        run to the next breakpoint");
+    println!("  b, break <line|f>  set a breakpoint on a line or function");
+    println!("  d, delete <n>      remove breakpoint n");
+    println!("  breaks             list breakpoints");
+    println!("  bt, stack          show the call stack");
+    println!("  p, print <var>     print a variable and its type");
+    println!("  vars               print every assigned variable");
+    println!("  l, list            show the source around the current line");
+    println!("  q, quit            stop debugging");
+}
+
+fn report(msg: &str) {
+    eprintln!("{}{}", "Error: ".red(), msg.red());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bimble::caps::Capabilities;
+    use bimble::check::{check_program, Checker};
+    use bimble::codegen::lower;
+
+    const SOURCE: &str = "may a = 1
+f()
+may b = 2
+ON f(){
+    may x = 1
+    g()
+    may y = 2
+}
+ON g(){
+    may z = 3
+}";
+
+    fn new_debugger() -> Debugger {
+        let lines: Vec<String> = SOURCE.lines().map(String::from).collect();
+        let mut checker = Checker::new(&[], &Capabilities::default());
+        let checked = check_program(&mut checker, &lines, &[]).unwrap();
+        let program = lower(&checked, true).ok().unwrap();
+        Debugger::new(Vm::new(program, Vec::new()), &checked)
+    }
+
+    /// The line `stop` paused at, or 0 once the program exited.
+    fn line(stop: Stop) -> u32 {
+        match stop {
+            Stop::Paused { line, .. } => line,
+            Stop::Exited => 0,
+            Stop::Failed { msg, .. } => panic!("{}", msg),
+        }
+    }
+
+    /// Lines visited by resuming with each of `modes` in turn.
+    fn trace(debugger: &mut Debugger, modes: &[Resume]) -> Vec<u32> {
+        modes
+            .iter()
+            .map(|mode| line(debugger.resume(*mode)))
+            .collect()
+    }
+
+    #[test]
+    fn step_enters_calls() {
+        let mut debugger = new_debugger();
+        assert_eq!(line(debugger.start()), 1);
+        assert_eq!(
+            trace(&mut debugger, &[Resume::Step; 9]),
+            [2, 5, 6, 10, 11, 7, 8, 3, 0]
+        );
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut debugger = new_debugger();
+        debugger.start();
+        assert_eq!(trace(&mut debugger, &[Resume::Next; 3]), [2, 3, 0]);
+
+        // Inside a function, Next stays there until it returns to the caller
+        let mut debugger = new_debugger();
+        debugger.start();
+        assert_eq!(trace(&mut debugger, &[Resume::Step; 2]), [2, 5]);
+        assert_eq!(trace(&mut debugger, &[Resume::Next; 4]), [6, 7, 8, 3]);
+    }
+
+    #[test]
+    fn out_returns_to_the_caller() {
+        let mut debugger = new_debugger();
+        debugger.start();
+        assert_eq!(trace(&mut debugger, &[Resume::Step; 4]), [2, 5, 6, 10]);
+        assert_eq!(trace(&mut debugger, &[Resume::Out; 3]), [7, 3, 0]);
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        let mut debugger = new_debugger();
+        debugger.break_at_function("g").unwrap();
+        assert_eq!(debugger.break_at_line(3), Ok(3));
+        // The ON line has no code of its own
+        assert_eq!(debugger.break_at_line(4), Ok(5));
+        assert!(debugger.break_at_line(12).is_err());
+        assert!(debugger.break_at_function("h").is_err());
+
+        assert_eq!(line(debugger.start()), 1);
+        let hits: Vec<_> = (0..4)
+            .map(|_| match debugger.resume(Resume::Continue) {
+                Stop::Paused { line, breakpoint } => (line, breakpoint),
+                stop => (line(stop), None),
+            })
+            .collect();
+        assert_eq!(hits, [(5, Some(2)), (10, Some(0)), (3, Some(1)), (0, None)]);
+    }
+
+    #[test]
+    fn breakpoints_interrupt_next_and_out() {
+        let mut debugger = new_debugger();
+        debugger.break_at_function("g").unwrap();
+        debugger.start();
+        assert_eq!(trace(&mut debugger, &[Resume::Next; 2]), [2, 10]);
+
+        let mut debugger = new_debugger();
+        debugger.break_at_line(10).unwrap();
+        debugger.start();
+        assert_eq!(trace(&mut debugger, &[Resume::Step; 2]), [2, 5]);
+        assert_eq!(trace(&mut debugger, &[Resume::Out; 2]), [10, 7]);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::bytecode::{Limits, Program, Value};
+use crate::caps::{builtin, Capabilities};
+use crate::check::{check_program, is_identifier, Checker};
+use crate::codegen::lower;
+use crate::preproc::filter_target;
+use crate::vm::{HostFn, Vm};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+/// Runs bimble source inside a Rust application, with native functions
+/// registered by that application callable from the program:
+///
+/// ```no_run
+/// use bimble::bytecode::Value;
+///
+/// let mut engine = bimble::Engine::new();
+/// engine.register("now", |_| Ok(Value::Int(1_700_000_000))).unwrap();
+/// engine.run("may t = now()\necholn(t)").unwrap();
+/// ```
+///
+/// Programs have no resources and, unless granted through `capabilities`,
+/// builtins like `read` are rejected. Relative capability paths resolve
+/// against the application's working directory.
+#[derive(Clone, Default)]
+pub struct Engine {
+    pub capabilities: Capabilities,
+    pub limits: Limits,
+    hosts: HashMap<String, HostFn>,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `f` callable as `name(args)`, both as a statement and as the
+    /// value of a `may` or an `echoln` argument. Registering a name again
+    /// replaces the previous function. Fails if `name` is a builtin or not a
+    /// valid function name.
+    pub fn register<F>(&mut self, name: &str, f: F) -> Result<(), String>
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        if !is_identifier(name) {
+            return Err(format!("'{}' is not a valid function name", name));
+        }
+        if builtin(name).is_some() {
+            return Err(format!("'{}' is a builtin function", name));
+        }
+        self.hosts.insert(name.to_string(), Arc::new(f));
+        Ok(())
+    }
+
+    /// Checks and lowers `source`, the text of a main.bb, for the platform
+    /// the application runs on. Errors read like the compiler's, e.g.
+    /// `Invalid argument to 'echoln' -> x at code (3): echoln(x)`.
+    pub fn compile(&self, source: &str) -> Result<Program, String> {
+        let platform = if cfg!(windows) { "windows" } else { "linux" };
+        let lines = filter_target(source, platform)
+            .map_err(|e| format!("{} at code ({}): {}", e.msg, e.index, e.line))?;
+
+        let mut checker = Checker::new(&[], &self.capabilities);
+        for name in self.hosts.keys() {
+            checker.host(name);
+        }
+        let checked = check_program(&mut checker, &lines, &[])?;
+
+        let mut program = lower(&checked, true).map_err(|e| {
+            let line = lines.get(e.index.wrapping_sub(1)).map_or("", |l| l.trim());
+            format!("{} at code ({}): {}", e.msg, e.index, line)
+        })?;
+        program.limits = self.limits;
+        program.capabilities = self.capabilities.clone();
+        Ok(program)
+    }
+
+    /// Compiles and runs `source`, printing `echoln` output to stdout.
+    pub fn run(&self, source: &str) -> Result<(), String> {
+        self.run_with_output(source, Box::new(io::stdout()))
+    }
+
+    /// Like `run`, but sends `echoln` output to `out`.
+    pub fn run_with_output(&self, source: &str, out: Box<dyn Write>) -> Result<(), String> {
+        let program = self.compile(source)?;
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        Vm::new(program, Vec::new())
+            .with_output(out)
+            .with_hosts(self.hosts.clone())
+            .run()
+            .map_err(|e| e.quote(&lines).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Captured;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn host_calls() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        let mut engine = Engine::new();
+        engine.register("now", |_| Ok(Value::Int(42))).unwrap();
+        engine
+            .register("log", move |args| {
+                seen.lock().unwrap().push(args[0].to_string());
+                Ok(Value::Int(0))
+            })
+            .unwrap();
+
+        let out = Captured::default();
+        let source = "may t = now()\nlog(t)\necholn(now())";
+        let result = engine.run_with_output(source, Box::new(out.clone()));
+        assert!(result.is_ok());
+        assert_eq!(String::from_utf8(out.take()).unwrap().trim(), "42");
+        assert_eq!(*calls.lock().unwrap(), ["42"]);
+
+        let err = Engine::new().compile("log()").err().unwrap();
+        assert!(err.starts_with("Undefined function call 'log'"));
+    }
+
+    #[test]
+    fn host_errors_stop_the_program() {
+        let mut engine = Engine::new();
+        engine
+            .register("fail", |_| Err("no clock".to_string()))
+            .unwrap();
+        let out = Captured::default();
+        let source = "fail()\necholn(\"done\")";
+        let err = engine
+            .run_with_output(source, Box::new(out.clone()))
+            .err()
+            .unwrap();
+        assert!(err.contains("no clock"));
+        assert!(out.take().is_empty());
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        let mut engine = Engine::new();
+        let err = engine.register("read", |_| Ok(Value::Int(0)));
+        assert_eq!(err, Err("'read' is a builtin function".to_string()));
+        let err = engine.register("2fast", |_| Ok(Value::Int(0)));
+        assert_eq!(err, Err("'2fast' is not a valid function name".to_string()));
+        assert!(engine.hosts.is_empty());
+    }
+
+    #[test]
+    fn engines_move_between_threads() {
+        let mut engine = Engine::new();
+        engine.register("now", |_| Ok(Value::Int(7))).unwrap();
+        let result = thread::spawn(move || engine.compile("may t = now()").map(|_| ()))
+            .join()
+            .unwrap();
+        assert!(result.is_ok());
+    }
+}
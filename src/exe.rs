@@ -0,0 +1,532 @@
+/// Name of the section holding the payload when it is embedded as a section.
+pub const SECTION_NAME: &str = ".bimble";
+
+/// Where `compile_binary` puts the payload inside the VM template.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmbedMode {
+    /// Payload and footer appended after the end of the executable.
+    Append,
+    /// Payload and footer stored in a `.bimble` ELF/PE section.
+    Section,
+}
+
+impl EmbedMode {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "append" => Ok(EmbedMode::Append),
+            "section" => Ok(EmbedMode::Section),
+            _ => Err(format!(
+                "Unknown embed mode '{}', expected 'append' or 'section'",
+                name
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EmbedMode::Append => "append",
+            EmbedMode::Section => "section",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExeFormat {
+    Elf,
+    Pe,
+    Unknown,
+}
+
+impl ExeFormat {
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"\x7fELF") {
+            ExeFormat::Elf
+        } else if data.starts_with(b"MZ") {
+            ExeFormat::Pe
+        } else {
+            ExeFormat::Unknown
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExeFormat::Elf => "ELF",
+            ExeFormat::Pe => "PE",
+            ExeFormat::Unknown => "unknown",
+        }
+    }
+}
+
+pub struct Section {
+    pub name: String,
+    /// File offset of the section contents.
+    pub offset: usize,
+    /// Size of the contents in the file.
+    pub size: usize,
+}
+
+/// Lists the sections of an ELF64 or PE32+ image.
+pub fn sections(data: &[u8]) -> Result<Vec<Section>, String> {
+    match ExeFormat::detect(data) {
+        ExeFormat::Elf => Elf::parse(data)?.sections(data),
+        ExeFormat::Pe => Pe::parse(data)?.sections(data),
+        ExeFormat::Unknown => Err("Not an ELF or PE executable".to_string()),
+    }
+}
+
+/// Returns the contents of the `.bimble` section, if the image has one.
+pub fn find_section<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let section = sections(data).ok()?.into_iter().find(|s| s.name == name)?;
+    slice(data, section.offset, section.size)
+}
+
+/// Returns a copy of the image with a new non-loaded data section appended.
+pub fn add_section(data: &[u8], name: &str, contents: &[u8]) -> Result<Vec<u8>, String> {
+    match ExeFormat::detect(data) {
+        ExeFormat::Elf => Elf::parse(data)?.add_section(data, name, contents),
+        ExeFormat::Pe => Pe::parse(data)?.add_section(data, name, contents),
+        ExeFormat::Unknown => Err("Not an ELF or PE executable".to_string()),
+    }
+}
+
+/// `len` bytes of `data` from `at`, or `None` when they run past the end. Header
+/// fields are untrusted, so the end is computed with `checked_add`.
+fn slice(data: &[u8], at: usize, len: usize) -> Option<&[u8]> {
+    data.get(at..at.checked_add(len)?)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, String> {
+    slice(data, at, 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Executable header is truncated".to_string())
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, String> {
+    slice(data, at, 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Executable header is truncated".to_string())
+}
+
+fn read_u64(data: &[u8], at: usize) -> Result<u64, String> {
+    slice(data, at, 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Executable header is truncated".to_string())
+}
+
+fn write_u16(data: &mut [u8], at: usize, value: u16) {
+    data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], at: usize, value: u32) {
+    data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(data: &mut [u8], at: usize, value: u64) {
+    data[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align(value: usize, to: usize) -> usize {
+    value.div_ceil(to) * to
+}
+
+/// Section header table of a little-endian ELF64 image.
+struct Elf {
+    shoff: usize,
+    shnum: usize,
+    shstrndx: usize,
+}
+
+const ELF_SHDR_LEN: usize = 64;
+
+impl Elf {
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 64 || data[4] != 2 || data[5] != 1 {
+            return Err("Only little-endian 64-bit ELF images are supported".to_string());
+        }
+        let elf = Self {
+            shoff: read_u64(data, 0x28)? as usize,
+            shnum: read_u16(data, 0x3c)? as usize,
+            shstrndx: read_u16(data, 0x3e)? as usize,
+        };
+        if read_u16(data, 0x3a)? as usize != ELF_SHDR_LEN {
+            return Err("Unexpected ELF section header size".to_string());
+        }
+        if elf.shnum == 0 || elf.shstrndx >= elf.shnum {
+            return Err("ELF image has no section name table".to_string());
+        }
+        if slice(data, elf.shoff, elf.shnum * ELF_SHDR_LEN).is_none() {
+            return Err("ELF section header table is truncated".to_string());
+        }
+        Ok(elf)
+    }
+
+    fn header(&self, i: usize) -> usize {
+        self.shoff + i * ELF_SHDR_LEN
+    }
+
+    fn sections(&self, data: &[u8]) -> Result<Vec<Section>, String> {
+        let strtab = self.header(self.shstrndx);
+        let str_offset = read_u64(data, strtab + 0x18)? as usize;
+        let str_size = read_u64(data, strtab + 0x20)? as usize;
+        let names = slice(data, str_offset, str_size)
+            .ok_or_else(|| "ELF section name table is truncated".to_string())?;
+
+        let mut out = Vec::new();
+        for i in 0..self.shnum {
+            let h = self.header(i);
+            let name_at = read_u32(data, h)? as usize;
+            let name = names
+                .get(name_at..)
+                .and_then(|n| n.split(|b| *b == 0).next())
+                .map(|n| String::from_utf8_lossy(n).into_owned())
+                .unwrap_or_default();
+            // SHT_NOBITS sections occupy no space in the file
+            let size = if read_u32(data, h + 4)? == 8 {
+                0
+            } else {
+                read_u64(data, h + 0x20)? as usize
+            };
+            out.push(Section {
+                name,
+                offset: read_u64(data, h + 0x18)? as usize,
+                size,
+            });
+        }
+        Ok(out)
+    }
+
+    /// Appends the contents, a grown copy of the section name table and a new
+    /// section header table, then points the ELF header at the new table.
+    /// Program headers are untouched, so the section is never loaded.
+    fn add_section(&self, data: &[u8], name: &str, contents: &[u8]) -> Result<Vec<u8>, String> {
+        if self.shnum + 1 >= 0xff00 {
+            return Err("ELF image has too many sections".to_string());
+        }
+        let mut out = data.to_vec();
+
+        let contents_at = out.len();
+        out.extend_from_slice(contents);
+
+        let strtab = self.header(self.shstrndx);
+        let str_offset = read_u64(data, strtab + 0x18)? as usize;
+        let str_size = read_u64(data, strtab + 0x20)? as usize;
+        let names = slice(data, str_offset, str_size)
+            .ok_or_else(|| "ELF section name table is truncated".to_string())?;
+        let strtab_at = out.len();
+        out.extend_from_slice(names);
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        let strtab_size = out.len() - strtab_at;
+
+        out.resize(align(out.len(), 8), 0);
+        let shoff = out.len();
+        out.extend_from_slice(&data[self.shoff..self.shoff + self.shnum * ELF_SHDR_LEN]);
+
+        let moved = shoff + self.shstrndx * ELF_SHDR_LEN;
+        write_u64(&mut out, moved + 0x18, strtab_at as u64);
+        write_u64(&mut out, moved + 0x20, strtab_size as u64);
+
+        let mut header = [0u8; ELF_SHDR_LEN];
+        write_u32(&mut header, 0, str_size as u32); // sh_name
+        write_u32(&mut header, 4, 1); // sh_type = SHT_PROGBITS
+        write_u64(&mut header, 0x18, contents_at as u64); // sh_offset
+        write_u64(&mut header, 0x20, contents.len() as u64); // sh_size
+        write_u64(&mut header, 0x30, 1); // sh_addralign
+        out.extend_from_slice(&header);
+
+        write_u64(&mut out, 0x28, shoff as u64);
+        write_u16(&mut out, 0x3c, (self.shnum + 1) as u16);
+        Ok(out)
+    }
+}
+
+/// Headers of a PE32+ image.
+struct Pe {
+    coff: usize,
+    optional: usize,
+    section_table: usize,
+    nsections: usize,
+}
+
+const PE_SHDR_LEN: usize = 40;
+
+impl Pe {
+    fn parse(data: &[u8]) -> Result<Self, String> {
+        let pe = read_u32(data, 0x3c)? as usize;
+        if slice(data, pe, 4) != Some(b"PE\0\0") {
+            return Err("Missing PE signature".to_string());
+        }
+        let coff = pe + 4;
+        let optional = coff + 20;
+        if read_u16(data, optional)? != 0x20b {
+            return Err("Only PE32+ images are supported".to_string());
+        }
+        let section_table = optional + read_u16(data, coff + 16)? as usize;
+        let nsections = read_u16(data, coff + 2)? as usize;
+        if data.len() < section_table + nsections * PE_SHDR_LEN {
+            return Err("PE section table is truncated".to_string());
+        }
+        Ok(Self {
+            coff,
+            optional,
+            section_table,
+            nsections,
+        })
+    }
+
+    fn sections(&self, data: &[u8]) -> Result<Vec<Section>, String> {
+        let mut out = Vec::new();
+        for i in 0..self.nsections {
+            let h = self.section_table + i * PE_SHDR_LEN;
+            let name = data[h..h + 8].split(|b| *b == 0).next().unwrap_or(&[]);
+            out.push(Section {
+                name: String::from_utf8_lossy(name).into_owned(),
+                offset: read_u32(data, h + 20)? as usize,
+                size: (read_u32(data, h + 16)? as usize).min(read_u32(data, h + 8)? as usize),
+            });
+        }
+        Ok(out)
+    }
+
+    /// Adds a read-only initialized-data section after the last one, placing
+    /// its raw data at the end of the file, and fixes up the section count,
+    /// SizeOfImage and checksum. The section header must fit in the existing
+    /// header padding.
+    fn add_section(&self, data: &[u8], name: &str, contents: &[u8]) -> Result<Vec<u8>, String> {
+        if name.len() > 8 {
+            return Err("PE section names are limited to 8 bytes".to_string());
+        }
+        let section_alignment = read_u32(data, self.optional + 32)? as usize;
+        let file_alignment = read_u32(data, self.optional + 36)? as usize;
+        let size_of_headers = read_u32(data, self.optional + 60)? as usize;
+
+        let new_header = self.section_table + self.nsections * PE_SHDR_LEN;
+        let first_raw = (0..self.nsections)
+            .map(|i| read_u32(data, self.section_table + i * PE_SHDR_LEN + 20))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|p| *p != 0)
+            .min()
+            .unwrap_or(size_of_headers as u32) as usize;
+        if new_header + PE_SHDR_LEN > size_of_headers.min(first_raw) {
+            return Err("No room for another section header in the PE image".to_string());
+        }
+
+        let virtual_address = self.next_virtual_address(data)?;
+
+        let mut out = data.to_vec();
+        out.resize(align(out.len(), file_alignment), 0);
+        let raw_at = out.len();
+        out.extend_from_slice(contents);
+        out.resize(align(out.len(), file_alignment), 0);
+
+        let mut header = [0u8; PE_SHDR_LEN];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_u32(&mut header, 8, contents.len() as u32); // VirtualSize
+        write_u32(&mut header, 12, virtual_address as u32);
+        write_u32(
+            &mut header,
+            16,
+            align(contents.len(), file_alignment) as u32,
+        );
+        write_u32(&mut header, 20, raw_at as u32);
+        // IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ
+        write_u32(&mut header, 36, 0x4000_0040);
+        out[new_header..new_header + PE_SHDR_LEN].copy_from_slice(&header);
+
+        write_u16(&mut out, self.coff + 2, (self.nsections + 1) as u16);
+        let size_of_image = align(virtual_address + contents.len(), section_alignment);
+        write_u32(&mut out, self.optional + 56, size_of_image as u32);
+        self.update_checksum(&mut out);
+        Ok(out)
+    }
+
+    /// The address a section added after the last one would be loaded at.
+    fn next_virtual_address(&self, data: &[u8]) -> Result<usize, String> {
+        let section_alignment = read_u32(data, self.optional + 32)? as usize;
+        let mut virtual_end = 0;
+        for i in 0..self.nsections {
+            let h = self.section_table + i * PE_SHDR_LEN;
+            let va = read_u32(data, h + 12)? as usize;
+            let vsize = read_u32(data, h + 8)? as usize;
+            let rsize = read_u32(data, h + 16)? as usize;
+            virtual_end = virtual_end.max(va + vsize.max(rsize));
+        }
+        Ok(align(virtual_end, section_alignment))
+    }
+
+    /// Offset of data directory `index` in the optional header, if present.
+    fn data_directory(&self, data: &[u8], index: usize) -> Result<Option<usize>, String> {
+        let count = read_u32(data, self.optional + 108)? as usize;
+        Ok((index < count).then_some(self.optional + 112 + index * 8))
+    }
+
+    /// Maps an address in the loaded image to its offset in the file.
+    fn file_offset(&self, data: &[u8], rva: usize) -> Result<Option<usize>, String> {
+        for i in 0..self.nsections {
+            let h = self.section_table + i * PE_SHDR_LEN;
+            let va = read_u32(data, h + 12)? as usize;
+            let rsize = read_u32(data, h + 16)? as usize;
+            if rva >= va && rva < va + rsize {
+                return Ok(Some(read_u32(data, h + 20)? as usize + rva - va));
+            }
+        }
+        Ok(None)
+    }
+
+    fn update_checksum(&self, out: &mut [u8]) {
+        write_u32(out, self.optional + 64, 0);
+        let checksum = pe_checksum(out, self.optional + 64);
+        write_u32(out, self.optional + 64, checksum);
+    }
+}
+
+/// Index of the resource table in the PE data directories.
+const RESOURCE_DIRECTORY: usize = 2;
+
+/// Adds a `.rsrc` section to a PE image and points the resource table at it.
+/// `build` receives the address the section will be loaded at, since the
+/// resource directory refers to its data by address.
+pub fn add_resource_section(
+    data: &[u8],
+    build: impl FnOnce(u32) -> Result<Vec<u8>, String>,
+) -> Result<Vec<u8>, String> {
+    if ExeFormat::detect(data) != ExeFormat::Pe {
+        return Err("Resources can only be added to PE images".to_string());
+    }
+    let pe = Pe::parse(data)?;
+    let directory = pe
+        .data_directory(data, RESOURCE_DIRECTORY)?
+        .ok_or_else(|| "PE image has no resource table entry".to_string())?;
+    if read_u32(data, directory)? != 0 {
+        return Err("PE image already has a resource section".to_string());
+    }
+
+    let rva = pe.next_virtual_address(data)?;
+    let contents = build(rva as u32)?;
+    let mut out = pe.add_section(data, ".rsrc", &contents)?;
+    write_u32(&mut out, directory, rva as u32);
+    write_u32(&mut out, directory + 4, contents.len() as u32);
+    pe.update_checksum(&mut out);
+    Ok(out)
+}
+
+/// Returns the address and contents of the resource table of a PE image.
+pub fn resource_section(data: &[u8]) -> Result<Option<(u32, &[u8])>, String> {
+    if ExeFormat::detect(data) != ExeFormat::Pe {
+        return Ok(None);
+    }
+    let pe = Pe::parse(data)?;
+    let Some(directory) = pe.data_directory(data, RESOURCE_DIRECTORY)? else {
+        return Ok(None);
+    };
+    let rva = read_u32(data, directory)? as usize;
+    let size = read_u32(data, directory + 4)? as usize;
+    if rva == 0 {
+        return Ok(None);
+    }
+    let offset = pe
+        .file_offset(data, rva)?
+        .ok_or_else(|| "PE resource table lies outside every section".to_string())?;
+    let table =
+        slice(data, offset, size).ok_or_else(|| "PE resource table is truncated".to_string())?;
+    Ok(Some((rva as u32, table)))
+}
+
+/// The PE image checksum: a 16-bit folded sum of the file (skipping the
+/// checksum field) plus the file length.
+fn pe_checksum(data: &[u8], checksum_at: usize) -> u32 {
+    let mut sum: u64 = 0;
+    for (i, chunk) in data.chunks(2).enumerate() {
+        if i * 2 == checksum_at || i * 2 == checksum_at + 2 {
+            continue;
+        }
+        let word = chunk[0] as u64 | (*chunk.get(1).unwrap_or(&0) as u64) << 8;
+        sum += word;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_images::{minimal_elf, minimal_pe};
+
+    /// Offset of the optional header in `minimal_pe`.
+    const OPTIONAL: usize = 0x58;
+
+    #[test]
+    fn elf_section_round_trip() {
+        let elf = minimal_elf();
+        let out = add_section(&elf, SECTION_NAME, b"payload bytes").unwrap();
+        assert_eq!(
+            find_section(&out, SECTION_NAME),
+            Some(&b"payload bytes"[..])
+        );
+        assert_eq!(read_u16(&out, 0x3c).unwrap(), 3);
+        assert_eq!(read_u64(&out, 0x28).unwrap() % 8, 0);
+        // Only the ELF header changes; the original sections still resolve
+        assert_eq!(&out[64..elf.len()], &elf[64..]);
+        let names: Vec<String> = sections(&out)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, ["", ".shstrtab", SECTION_NAME]);
+    }
+
+    #[test]
+    fn pe_section_round_trip() {
+        let pe = minimal_pe();
+        let contents = vec![0xab; 0x300];
+        let out = add_section(&pe, SECTION_NAME, &contents).unwrap();
+        assert_eq!(find_section(&out, SECTION_NAME), Some(&contents[..]));
+        assert_eq!(read_u16(&out, 0x46).unwrap(), 2);
+        // The new section is loaded at 0x2000 and takes one page
+        assert_eq!(read_u32(&out, OPTIONAL + 56).unwrap(), 0x3000);
+        assert_eq!(out.len() % 0x200, 0);
+
+        let checksum = read_u32(&out, OPTIONAL + 64).unwrap();
+        assert_ne!(checksum, 0);
+        assert_eq!(checksum, pe_checksum(&out, OPTIONAL + 64));
+    }
+
+    #[test]
+    fn pe_checksum_skips_its_field_and_adds_the_length() {
+        let data = [1, 0, 2, 0, 0xff, 0xff, 0xff, 0xff, 3, 0];
+        assert_eq!(pe_checksum(&data, 4), 1 + 2 + 3 + 10);
+        // Carries are folded back into the low 16 bits
+        assert_eq!(pe_checksum(&[0xff, 0xff, 2, 0], 8), 2 + 4);
+    }
+
+    #[test]
+    fn pe_without_header_room_is_rejected() {
+        let mut pe = minimal_pe();
+        write_u32(&mut pe, OPTIONAL + 60, 0x170);
+        assert!(add_section(&pe, SECTION_NAME, b"x").is_err());
+        assert!(add_section(&minimal_pe(), ".toolongname", b"x").is_err());
+    }
+
+    #[test]
+    fn crafted_offsets_are_errors() {
+        let mut elf = minimal_elf();
+        write_u64(&mut elf, 0x28, u64::MAX - 8);
+        assert!(sections(&elf).is_err());
+
+        let mut elf = minimal_elf();
+        // Name table offset near usize::MAX
+        write_u64(&mut elf, 80 + ELF_SHDR_LEN + 0x18, u64::MAX - 4);
+        assert!(sections(&elf).is_err());
+        assert!(add_section(&elf, SECTION_NAME, b"x").is_err());
+
+        let mut elf = add_section(&minimal_elf(), SECTION_NAME, b"x").unwrap();
+        let shoff = read_u64(&elf, 0x28).unwrap() as usize;
+        write_u64(&mut elf, shoff + 2 * ELF_SHDR_LEN + 0x18, u64::MAX);
+        assert_eq!(find_section(&elf, SECTION_NAME), None);
+
+        assert!(sections(b"MZ").is_err());
+        assert!(sections(b"not an executable").is_err());
+    }
+}
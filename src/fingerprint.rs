@@ -0,0 +1,197 @@
+use crate::config::{BuildProfile, CompilerConfig};
+use crate::datagen::BuildOptions;
+use crate::manifest::sha256_hex;
+use bimble::resources::Resource;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+/// Name of the fingerprint cache inside the build directory.
+const CACHE_FILE: &str = ".fingerprints";
+
+/// What the previous build recorded for one target.
+pub struct CacheEntry {
+    pub platform: String,
+    pub fingerprint: String,
+    /// Artifact path relative to the build directory.
+    pub artifact: String,
+    pub sha256: String,
+}
+
+/// Hashes everything that can change the artifact of `platform`: the
+/// compiler version, main.bb, cfg.bcf, defines, limits, profile, build
+/// options, resources, the Windows icon and the VM template.
+#[allow(clippy::too_many_arguments)]
+pub fn fingerprint(
+    platform: &str,
+    source_code: &str,
+    project_path: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+    options: &BuildOptions,
+    resources: &[Resource],
+    base_vm: &str,
+) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+
+    field(env!("CARGO_PKG_VERSION").as_bytes());
+    field(platform.as_bytes());
+    field(source_code.as_bytes());
+    field(&fs::read(format!("{}/cfg.bcf", project_path)).unwrap_or_default());
+    for (key, value) in &config.defines {
+        field(format!("{}={}", key, value).as_bytes());
+    }
+    field(format!("{:?}", config.limits).as_bytes());
+    field(format!("{:?}", profile).as_bytes());
+    field(
+        format!(
+            "{:?} {:?} {} {:?}",
+            options.payload_format, options.codec, options.obfuscate, options.embed
+        )
+        .as_bytes(),
+    );
+    for resource in resources {
+        field(resource.name.as_bytes());
+        field(&resource.data);
+    }
+    if let Some(icon) = &config.target(platform).icon {
+        field(&fs::read(format!("{}/{}", project_path, icon)).unwrap_or_default());
+    }
+    field(&fs::read(base_vm).unwrap_or_default());
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn read_cache(build_dir: &str) -> Vec<CacheEntry> {
+    let Ok(content) = fs::read_to_string(format!("{}/{}", build_dir, CACHE_FILE)) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split('\t');
+            Some(CacheEntry {
+                platform: parts.next()?.to_string(),
+                fingerprint: parts.next()?.to_string(),
+                artifact: parts.next()?.to_string(),
+                sha256: parts.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn write_cache(build_dir: &str, entries: &[CacheEntry]) -> Result<(), String> {
+    let content: String = entries
+        .iter()
+        .map(|e| {
+            format!(
+                "{}\t{}\t{}\t{}\n",
+                e.platform, e.fingerprint, e.artifact, e.sha256
+            )
+        })
+        .collect();
+    let cache_file = format!("{}/{}", build_dir, CACHE_FILE);
+    fs::write(&cache_file, content).map_err(|e| format!("Unable to write {}: {}", cache_file, e))
+}
+
+/// Returns the cached entry for `platform` when its fingerprint matches and
+/// the artifact on disk is still the one that build produced.
+pub fn up_to_date<'a>(
+    cache: &'a [CacheEntry],
+    build_dir: &str,
+    platform: &str,
+    fingerprint: &str,
+) -> Option<&'a CacheEntry> {
+    let entry = cache
+        .iter()
+        .find(|e| e.platform == platform && e.fingerprint == fingerprint)?;
+    let data = fs::read(format!("{}/{}", build_dir, entry.artifact)).ok()?;
+    (sha256_hex(&data) == entry.sha256).then_some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bimble::exe::EmbedMode;
+    use bimble::payload::Codec;
+    use std::env;
+
+    fn options() -> BuildOptions {
+        BuildOptions {
+            payload_format: None,
+            codec: Codec::None,
+            obfuscate: false,
+            embed: EmbedMode::Append,
+            force: false,
+        }
+    }
+
+    #[test]
+    fn inputs_change_the_fingerprint() {
+        let dir = env::temp_dir().join(format!("bimble-fingerprint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cfg.bcf"), "Name : fp\nAuthors : A\nVer : 1\n").unwrap();
+        let project = dir.to_str().unwrap();
+        let config = CompilerConfig::new();
+        let (dev, release) = (BuildProfile::dev(), BuildProfile::release());
+        let vm = dir.join("vm").display().to_string();
+        let print = |source: &str, profile: &BuildProfile, options: &BuildOptions| {
+            fingerprint(
+                "linux",
+                source,
+                project,
+                &config,
+                profile,
+                options,
+                &[],
+                &vm,
+            )
+        };
+
+        let base = print("echoln(\"a\")", &dev, &options());
+        assert_eq!(base, print("echoln(\"a\")", &dev, &options()));
+        assert_ne!(base, print("echoln(\"b\")", &dev, &options()));
+        assert_ne!(base, print("echoln(\"a\")", &release, &options()));
+        let compressed = BuildOptions {
+            codec: Codec::Deflate,
+            ..options()
+        };
+        assert_ne!(base, print("echoln(\"a\")", &dev, &compressed));
+        fs::write(&vm, "template").unwrap();
+        assert_ne!(base, print("echoln(\"a\")", &dev, &options()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cache_tracks_the_artifact() {
+        let dir = env::temp_dir().join(format!("bimble-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let build = dir.to_str().unwrap();
+        fs::write(dir.join("app"), "artifact").unwrap();
+        let entry = CacheEntry {
+            platform: "linux".to_string(),
+            fingerprint: "f1".to_string(),
+            artifact: "app".to_string(),
+            sha256: sha256_hex(b"artifact"),
+        };
+        write_cache(build, &[entry]).unwrap();
+
+        let cache = read_cache(build);
+        assert_eq!(cache.len(), 1);
+        assert!(up_to_date(&cache, build, "linux", "f1").is_some());
+        assert!(up_to_date(&cache, build, "linux", "f2").is_none());
+        assert!(up_to_date(&cache, build, "windows", "f1").is_none());
+        fs::write(dir.join("app"), "tampered").unwrap();
+        assert!(up_to_date(&cache, build, "linux", "f1").is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use colored::Colorize;
+use std::process::{exit, Command};
+
+/// Runs each hook command through the platform shell from the project folder.
+/// The build stops at the first hook that cannot be started or exits non-zero.
+pub fn run_hooks(stage: &str, commands: &[String], project_path: &str, env: &[(&str, String)]) {
+    for command in commands {
+        println!(
+            "{}{}{}{}",
+            "Running ".blue(),
+            stage.bold().cyan(),
+            " hook -> ".blue(),
+            command.cyan()
+        );
+
+        if let Err(e) = run_hook(stage, command, project_path, env) {
+            eprintln!("{}{}", "Error: ".red(), e.bold().red());
+            exit(-1);
+        }
+    }
+}
+
+/// Runs one hook command, failing if it cannot be started or exits non-zero.
+fn run_hook(
+    stage: &str,
+    command: &str,
+    project_path: &str,
+    env: &[(&str, String)],
+) -> Result<(), String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).current_dir(project_path);
+    for (key, value) in env {
+        shell.env(key, value);
+    }
+
+    match shell.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!(
+            "{} hook failed with {} -> {}",
+            stage, status, command
+        )),
+        Err(err) => Err(format!(
+            "Unable to run {} hook: {}. Error details: {}",
+            stage, command, err
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn failing_hooks_report_their_exit_code() {
+        let dir = env::temp_dir().display().to_string();
+        assert_eq!(run_hook("pre-build", "exit 0", &dir, &[]), Ok(()));
+
+        let err = run_hook("pre-build", "exit 3", &dir, &[]).unwrap_err();
+        assert!(err.starts_with("pre-build hook failed with "), "{}", err);
+        assert!(err.contains('3') && err.ends_with("-> exit 3"), "{}", err);
+    }
+
+    #[test]
+    fn hooks_that_cannot_start_are_errors() {
+        let missing = env::temp_dir().join(format!("bimble-hooks-missing-{}", std::process::id()));
+        let err =
+            run_hook("post-build", "exit 0", &missing.display().to_string(), &[]).unwrap_err();
+        assert!(
+            err.starts_with("Unable to run post-build hook: exit 0"),
+            "{}",
+            err
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hooks_run_in_the_project_with_the_build_env() {
+        let dir = env::temp_dir().join(format!("bimble-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.bb"), "").unwrap();
+        let path = dir.display().to_string();
+        let env = [("BIMBLE_PROFILE", "release".to_string())];
+
+        let result = run_hook(
+            "pre-build",
+            "test -f main.bb && test \"$BIMBLE_PROFILE\" = release",
+            &path,
+            &env,
+        );
+        let missing = run_hook("pre-build", "test -n \"$BIMBLE_PROFILE\"", &path, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use bimble::bytecode::{Limits, Value};
+use bimble::caps::{self, Capabilities};
+use bimble::check::{builtin_call, resource_name, CheckedLine, Stmt, VT};
+use bimble::codegen::unquote;
+use bimble::resources::Resource;
+use bimble::vm::{size, Limit, LIMIT_EXIT_CODE, MAX_DEPTH};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// Host functions are only registered with bimble::Engine, which runs
+/// programs on the VM.
+const HOST_ONLY: &str = "Host functions are only available through bimble::Engine";
+
+pub struct InterpError {
+    pub index: usize,
+    pub msg: String,
+    /// Set when one of the `Limits` stopped the program.
+    pub limit: Option<Limit>,
+}
+
+impl InterpError {
+    fn new(index: usize, msg: String) -> Self {
+        Self {
+            index,
+            msg,
+            limit: None,
+        }
+    }
+
+    /// Process exit code for `bimble run --interp`, the same as the VM's.
+    pub fn exit_code(&self) -> i32 {
+        match self.limit {
+            Some(_) => LIMIT_EXIT_CODE,
+            None => -1,
+        }
+    }
+}
+
+/// A statement of a block together with its line in main.bb.
+type Block = Vec<(usize, Stmt)>;
+
+/// Executes checked statements directly, without lowering them to bytecode.
+/// Functions and variables persist between calls to `run`, so a program can
+/// be fed in pieces.
+///
+/// `Limits` apply to each call to `run`: instructions count statements, time
+/// is read after every statement, and memory counts variables and call
+/// frames.
+pub struct Interpreter {
+    pub fns: HashMap<String, Rc<Block>>,
+    pub vars: HashMap<String, (Value, VT)>,
+    resources: Vec<Resource>,
+    capabilities: Capabilities,
+    limits: Limits,
+    /// Bytes held by `vars`.
+    memory: u64,
+    out: Box<dyn Write>,
+}
+
+impl Interpreter {
+    pub fn new(resources: Vec<Resource>, capabilities: Capabilities) -> Self {
+        Self {
+            fns: HashMap::new(),
+            vars: HashMap::new(),
+            resources,
+            capabilities,
+            limits: Limits::default(),
+            memory: 0,
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sends `echoln` output to `out` instead of stdout.
+    // Only the tests capture the interpreter's output so far
+    #[allow(dead_code)]
+    pub fn with_output(mut self, out: Box<dyn Write>) -> Self {
+        self.out = out;
+        self
+    }
+
+    /// Registers the functions defined in `checked`, then executes its
+    /// top-level statements in order.
+    pub fn run(&mut self, checked: &[CheckedLine]) -> Result<(), InterpError> {
+        let main = self.define(checked)?;
+        self.exec(Rc::new(main))?;
+        self.out
+            .flush()
+            .map_err(|e| InterpError::new(0, format!("Unable to write output: {}", e)))
+    }
+
+    /// Splits `checked` into top-level statements and function bodies.
+    fn define(&mut self, checked: &[CheckedLine]) -> Result<Block, InterpError> {
+        let mut main = Block::new();
+        let mut current: Option<(usize, String, Block)> = None;
+
+        for line in checked {
+            let err = |msg: &str| InterpError::new(line.index, msg.to_string());
+            match &line.stmt {
+                Stmt::Fn { name, has_body } => {
+                    if current.is_some() {
+                        return Err(err("Nested function definitions are not supported"));
+                    }
+                    if *has_body {
+                        current = Some((line.index, name.clone(), Block::new()));
+                    } else {
+                        self.fns.insert(name.clone(), Rc::new(Block::new()));
+                    }
+                }
+                Stmt::End => match current.take() {
+                    Some((_, name, body)) => {
+                        self.fns.insert(name, Rc::new(body));
+                    }
+                    None => return Err(err("Unexpected '}' outside of a function body")),
+                },
+                stmt => match &mut current {
+                    Some((_, _, body)) => body.push((line.index, stmt.clone())),
+                    None => main.push((line.index, stmt.clone())),
+                },
+            }
+        }
+
+        match current {
+            Some((index, _, _)) => Err(InterpError::new(
+                index,
+                "Function body is missing its closing '}'".to_string(),
+            )),
+            None => Ok(main),
+        }
+    }
+
+    /// Executes `main`. ON function calls run on an explicit frame stack, so
+    /// deep recursion does not overflow the native one.
+    fn exec(&mut self, main: Rc<Block>) -> Result<(), InterpError> {
+        let limits = self.limits;
+        let max_depth = limits.depth.map_or(MAX_DEPTH, |depth| depth as usize);
+        let started = Instant::now();
+        let mut executed = 0;
+        // Blocks being executed with the position of their next statement
+        let mut frames = vec![(main, 0)];
+        while let Some((block, next)) = frames.last_mut() {
+            let block = Rc::clone(block);
+            let Some((index, stmt)) = block.get(*next) else {
+                frames.pop();
+                continue;
+            };
+            *next += 1;
+            let err = |msg: String| InterpError::new(*index, msg);
+            let exceed = |limit: Limit| InterpError {
+                index: *index,
+                msg: limit.describe(&limits),
+                limit: Some(limit),
+            };
+
+            executed += 1;
+            if limits.instructions.is_some_and(|max| executed > max) {
+                return Err(exceed(Limit::Instructions));
+            }
+            match stmt {
+                Stmt::Let { name, val, vt } => {
+                    let value = if resource_name(val).is_some() || builtin_call(val).is_some() {
+                        self.argument(val).map_err(err)?
+                    } else {
+                        literal(val, vt).map_err(err)?
+                    };
+                    self.memory += size(&value);
+                    if let Some((old, _)) = self.vars.insert(name.clone(), (value, vt.clone())) {
+                        self.memory -= size(&old);
+                    }
+                }
+                Stmt::Echo(args) => {
+                    let mut line = String::new();
+                    for arg in args {
+                        line.push_str(&self.argument(arg).map_err(err)?.to_string());
+                    }
+                    writeln!(self.out, "{}", line)
+                        .map_err(|e| err(format!("Unable to write output: {}", e)))?;
+                }
+                Stmt::Call(name) => {
+                    let body = self
+                        .fns
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| err(format!("Undefined function call '{}'", name)))?;
+                    // The first frame is the top-level code
+                    if frames.len() > max_depth {
+                        return Err(match limits.depth {
+                            Some(_) => exceed(Limit::Depth),
+                            None => err(format!("Recursion depth limit of {} exceeded", MAX_DEPTH)),
+                        });
+                    }
+                    frames.push((body, 0));
+                }
+                Stmt::Builtin { id, args } => {
+                    self.builtin(*id, args).map_err(err)?;
+                }
+                Stmt::Host { .. } => return Err(err(HOST_ONLY.to_string())),
+                Stmt::Fn { .. } | Stmt::End => unreachable!(),
+            }
+
+            // Read after the statement, which may have blocked in a builtin
+            if limits.time.is_some_and(|max| started.elapsed() > max) {
+                return Err(exceed(Limit::Time));
+            }
+            let frame_bytes = (frames.len() * size_of::<(Rc<Block>, usize)>()) as u64;
+            if limits
+                .memory
+                .is_some_and(|max| self.memory + frame_bytes > max)
+            {
+                return Err(exceed(Limit::Memory));
+            }
+        }
+        Ok(())
+    }
+
+    fn argument(&mut self, arg: &str) -> Result<Value, String> {
+        if let Some(resource) = resource_name(arg) {
+            self.resource(resource)
+        } else if let Some((id, args)) = builtin_call(arg) {
+            Ok(self
+                .builtin(id, &args)?
+                .unwrap_or(Value::Str(String::new())))
+        } else if arg.starts_with('"') || arg.starts_with('\'') {
+            Ok(Value::Str(unquote(arg)))
+        } else {
+            self.vars
+                .get(arg)
+                .map(|(value, _)| value.clone())
+                .ok_or_else(|| format!("Use of undeclared variable '{}'", arg))
+        }
+    }
+
+    fn builtin(&mut self, id: usize, args: &[String]) -> Result<Option<Value>, String> {
+        let args = args
+            .iter()
+            .map(|arg| self.argument(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        // A spawned process writes to the same terminal
+        self.out
+            .flush()
+            .map_err(|e| format!("Unable to write output: {}", e))?;
+        caps::call(id, &args, &self.capabilities)
+    }
+
+    fn resource(&self, name: &str) -> Result<Value, String> {
+        self.resources
+            .iter()
+            .find(|r| r.name == name)
+            .map(|r| Value::Str(String::from_utf8_lossy(&r.data).into_owned()))
+            .ok_or_else(|| format!("Resource '{}' is not embedded in this program", name))
+    }
+}
+
+/// Evaluates the right-hand side of a `may` the same way the code generator
+/// turns it into a constant.
+fn literal(val: &str, vt: &VT) -> Result<Value, String> {
+    Ok(match vt {
+        VT::Str => Value::Str(unquote(val)),
+        VT::Int => Value::Int(val.parse().map_err(|_| "Invalid integer".to_string())?),
+        VT::Float => Value::Float(val.parse().map_err(|_| "Invalid float".to_string())?),
+        VT::RT => Value::Str(val.to_string()),
+        VT::Host => return Err(HOST_ONLY.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bimble::check::{check_program, Checker};
+    use bimble::codegen::lower;
+    use bimble::vm::{Captured, Vm};
+
+    /// Runs `source` through both the interpreter and the VM and returns what
+    /// each printed.
+    fn both(source: &str, resources: &[(&str, &str)]) -> (String, String) {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let names: Vec<String> = resources.iter().map(|r| r.0.to_string()).collect();
+        let resources = || {
+            resources
+                .iter()
+                .map(|(name, data)| Resource {
+                    name: name.to_string(),
+                    data: data.as_bytes().to_vec(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut checker = Checker::new(&names, &Capabilities::default());
+        let checked = check_program(&mut checker, &lines, &[]).unwrap();
+
+        let out = Captured::default();
+        let mut interp = Interpreter::new(resources(), Capabilities::default())
+            .with_output(Box::new(out.clone()));
+        assert!(interp.run(&checked).is_ok());
+        let interpreted = String::from_utf8(out.take()).unwrap();
+
+        let program = lower(&checked, true).ok().unwrap();
+        let result = Vm::new(program, resources())
+            .with_output(Box::new(out.clone()))
+            .run();
+        assert!(result.is_ok());
+        (interpreted, String::from_utf8(out.take()).unwrap())
+    }
+
+    #[test]
+    fn interpreter_matches_the_vm() {
+        let resources = [("greeting.txt", "hello")];
+        let programs = [
+            "may name = \"joy\"\nmay n = 3\nmay f = 1.5\necholn(\"hi \", name, ' ', n, f)",
+            "outer()\nON outer(){\n    echoln(\"outer\")\n    inner()\n    echoln(\"back\")\n}\nON inner(){\n    echoln(\"inner\")\n}",
+            "may text = resource(\"greeting.txt\")\necholn(text, \" \", resource(\"greeting.txt\"))",
+            "may x = 1\necholn(x)\nset()\necholn(x)\nmay x = \"three\"\necholn(x)\nON set(){\n    may x = 2\n}",
+        ];
+        for source in programs {
+            let (interpreted, vm) = both(source, &resources);
+            assert!(!interpreted.is_empty(), "{}", source);
+            assert_eq!(interpreted, vm, "{}", source);
+        }
+        assert_eq!(both(programs[3], &[]).0, "1\n2\nthree\n");
+    }
+
+    #[test]
+    fn host_calls_are_an_error() {
+        let mut interp = Interpreter::new(Vec::new(), Capabilities::default());
+        let checked = [CheckedLine {
+            index: 1,
+            stmt: Stmt::Host {
+                name: "now".to_string(),
+                args: Vec::new(),
+            },
+        }];
+        let e = interp.run(&checked).err().unwrap();
+        assert_eq!((e.index, e.msg.as_str()), (1, HOST_ONLY));
+    }
+
+    #[test]
+    fn limits_apply_to_each_run() {
+        // f() recurses forever
+        let checked = [
+            CheckedLine {
+                index: 1,
+                stmt: Stmt::Fn {
+                    name: "f".to_string(),
+                    has_body: true,
+                },
+            },
+            CheckedLine {
+                index: 2,
+                stmt: Stmt::Call("f".to_string()),
+            },
+            CheckedLine {
+                index: 3,
+                stmt: Stmt::End,
+            },
+            CheckedLine {
+                index: 4,
+                stmt: Stmt::Call("f".to_string()),
+            },
+        ];
+        let run = |limits: Limits| {
+            let mut interp =
+                Interpreter::new(Vec::new(), Capabilities::default()).with_limits(limits);
+            let e = interp.run(&checked).err().unwrap();
+            assert_eq!(e.exit_code(), LIMIT_EXIT_CODE);
+            e.limit
+        };
+        let none = Limits::default();
+        assert_eq!(
+            run(Limits {
+                depth: Some(20),
+                ..none
+            }),
+            Some(Limit::Depth)
+        );
+        assert_eq!(
+            run(Limits {
+                instructions: Some(20),
+                ..none
+            }),
+            Some(Limit::Instructions)
+        );
+        assert_eq!(
+            run(Limits {
+                memory: Some(1024),
+                ..none
+            }),
+            Some(Limit::Memory)
+        );
+
+        // Without a Depth limit recursion still ends, with a plain error
+        let mut interp = Interpreter::new(Vec::new(), Capabilities::default());
+        let e = interp.run(&checked).err().unwrap();
+        assert_eq!((e.limit, e.exit_code()), (None, -1));
+    }
+}
@@ -0,0 +1,20 @@
+//! Pieces shared by the `bimble` compiler and the `bimble-vm` runtime: the
+//! checker, code generator, bytecode format, payload container, executable
+//! sections, resources and the VM itself. `Engine` wraps them for Rust
+//! applications that embed bimble as a scripting layer.
+
+pub mod bytecode;
+pub mod caps;
+pub mod check;
+pub mod codegen;
+pub mod engine;
+pub mod exe;
+pub mod payload;
+pub mod preproc;
+pub mod resources;
+pub mod vm;
+
+#[cfg(test)]
+mod test_images;
+
+pub use engine::Engine;
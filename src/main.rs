@@ -1,15 +1,156 @@
-mod check;
+mod artifact;
+mod config;
+mod dap;
 mod datagen;
+mod debug;
+mod fingerprint;
+mod hooks;
+mod interp;
+mod manifest;
+mod profiler;
+mod repl;
+// Shared with the library's tests; only the PE image is used here
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "test_images.rs"]
+mod test_images;
+mod winres;
 
 use colored::Colorize;
-use check::check;
-use datagen::compile_project;
+use artifact::{inspect_artifact, verify_artifact};
+use bimble::exe::EmbedMode;
+use bimble::payload::{Codec, PayloadFormat};
+use config::{parse_config, parse_define, set_limit};
+use dap::dap;
+use datagen::{compile_project, hook_env, run_project, BuildOptions};
+use debug::debug_project;
+use hooks::run_hooks;
+use profiler::profile_project;
+use repl::repl;
 use std::{env::args, fs, path::Path, process::exit};
 
 fn main() {
-    let args: Vec<String> = args().collect();
+    let args: Vec<String> = args().skip(1).collect();
 
-    if args.len() != 2 {
+    if let Some(command @ ("verify" | "inspect")) = args.first().map(String::as_str) {
+        if args.len() != 2 {
+            eprintln!(
+                "{}",
+                format!("Error: Usage: bimble {} <artifact>", command).bold().red()
+            );
+            exit(-1);
+        }
+        let result = if command == "verify" {
+            verify_artifact(&args[1])
+        } else {
+            inspect_artifact(&args[1])
+        };
+        if let Err(e) = result {
+            eprintln!("{}{}", "Error: ".bold().red(), e.bold().red());
+            exit(-1);
+        }
+        return;
+    }
+
+    if let Some(command @ ("repl" | "dap")) = args.first().map(String::as_str) {
+        // `bimble repl <folder>` applies the project's [limits], [capabilities]
+        // and defines
+        let usage = match command {
+            "repl" if args.len() <= 2 => None,
+            "repl" => Some("bimble repl [project]"),
+            _ if args.len() == 1 => None,
+            _ => Some("bimble dap"),
+        };
+        if let Some(usage) = usage {
+            eprintln!("{}", format!("Error: Usage: {}", usage).bold().red());
+            exit(-1);
+        }
+        if command == "repl" {
+            let project = args.get(1).map(|pf| (pf.as_str(), parse_config(pf)));
+            repl(project.as_ref().map(|(pf, config)| (*pf, config)));
+        } else {
+            dap();
+        }
+        return;
+    }
+
+    // `bimble run|debug <folder>` execute the program in-process instead of building it
+    let command = args
+        .first()
+        .map(String::as_str)
+        .filter(|c| matches!(*c, "run" | "debug"));
+    let mut interp = false;
+    let mut profiling = false;
+    let mut release = false;
+    let mut payload_format = None;
+    let mut codec = None;
+    let mut obfuscate = false;
+    let mut force = false;
+    let mut embed = None;
+    let mut folders = Vec::new();
+    let mut defines = Vec::new();
+    let mut limits = Vec::new();
+
+    let mut iter = args.iter().skip(command.is_some() as usize);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--release" => release = true,
+            // Output options mean nothing when nothing is written
+            "--legacy-payload" | "--compress" | "--obfuscate" | "--force" if command.is_some() => {
+                build_only_flag(arg, command)
+            }
+            _ if command.is_some()
+                && (arg.starts_with("--compress=") || arg.starts_with("--embed=")) =>
+            {
+                build_only_flag(arg, command)
+            }
+            // Needs an original lb.bjb/wb.bjb template next to the compiler;
+            // bimble only builds bimble-vm templates
+            "--legacy-payload" => payload_format = Some(PayloadFormat::Legacy),
+            "--compress" => codec = Some(Codec::Deflate),
+            "--obfuscate" => obfuscate = true,
+            "--force" => force = true,
+            "--interp" if command == Some("run") => interp = true,
+            "--profile" if command == Some("run") => profiling = true,
+            _ if arg.starts_with("--compress=") => match Codec::parse(&arg[11..]) {
+                Ok(c) => codec = Some(c),
+                Err(e) => {
+                    eprintln!("{}{}", "Error: ".bold().red(), e.bold().red());
+                    exit(-1);
+                }
+            },
+            _ if arg.starts_with("--embed=") => match EmbedMode::parse(&arg[8..]) {
+                Ok(mode) => embed = Some(mode),
+                Err(e) => {
+                    eprintln!("{}{}", "Error: ".bold().red(), e.bold().red());
+                    exit(-1);
+                }
+            },
+            // --max-instructions=N, --max-depth=N, --max-memory=SIZE, --max-time=MS
+            _ if arg.starts_with("--max-") && arg.contains('=') => {
+                limits.push(arg[6..].split_once('=').unwrap())
+            }
+            "-D" => match iter.next() {
+                Some(define) => defines.push(define.as_str()),
+                None => {
+                    eprintln!("{}", "Error: Missing KEY=VALUE after -D".bold().red());
+                    exit(-1);
+                }
+            },
+            _ if arg.starts_with("-D") => defines.push(&arg[2..]),
+            _ if arg.starts_with("--") => {
+                eprintln!(
+                    "{}{}",
+                    "Error: Unknown flag -> ".bold().red(),
+                    arg.bold().red()
+                );
+                exit(-1);
+            }
+            _ => folders.push(arg),
+        }
+    }
+
+    if folders.len() != 1 {
         eprintln!(
             "{}",
             "Error: Invalid number of arguments. Please provide exactly one project folder."
@@ -19,7 +160,7 @@ fn main() {
         exit(-1);
     }
 
-    let pf = &args[1];
+    let pf = folders[0];
     if let Err(e) = validate_project_folder(pf) {
         eprintln!("{}", e);
         exit(-1);
@@ -31,11 +172,89 @@ fn main() {
         exit(-1);
     }
 
+    let mut config = parse_config(pf);
+    for define in defines {
+        match parse_define(define) {
+            Ok((k, v)) => config.set_define(k, v),
+            Err(e) => {
+                eprintln!("{}{}", "Error: ".bold().red(), e.bold().red());
+                exit(-1);
+            }
+        }
+    }
+    for (key, value) in limits {
+        if let Err(e) = set_limit(&mut config.limits, key, value) {
+            eprintln!("{}{}", "Error: ".bold().red(), e.bold().red());
+            exit(-1);
+        }
+    }
+    let profile = config.profile(release);
+    if interp && profiling {
+        eprintln!(
+            "{}",
+            "Error: --profile runs on the VM and cannot be combined with --interp"
+                .bold()
+                .red()
+        );
+        exit(-1);
+    }
+    if let Some(command) = command {
+        match fs::read_to_string(&mf) {
+            Ok(code) if profiling => profile_project(pf, &code, &config, profile),
+            Ok(code) if command == "run" => run_project(pf, &code, &config, profile, interp),
+            Ok(code) => debug_project(pf, &code, &config, profile),
+            Err(e) => {
+                eprintln!(
+                    "{}{}{}: {}",
+                    "Error: ".bold().red(),
+                    "Cannot read code from 'main.bb' at -> ".bold().red(),
+                    mf.bold().red(),
+                    e.to_string().bold().red()
+                );
+                exit(-1);
+            }
+        }
+        return;
+    }
+    let codec = codec.unwrap_or(config.compress);
+    let obfuscate = obfuscate || config.obfuscate;
+    let embed = embed.unwrap_or(config.embed);
+    if obfuscate {
+        println!(
+            "{}",
+            "Note: payload obfuscation only hides the program from casual inspection; the key ships inside the artifact, so it is not a security guarantee."
+                .yellow()
+        );
+    }
+
+    run_hooks(
+        "pre-build",
+        &config.pre_build,
+        pf,
+        &hook_env(pf, &config, profile),
+    );
+
     match fs::read_to_string(&mf) {
         Ok(code) => {
-            let cds: Vec<String> = code.lines().map(String::from).collect();
-            check(cds);
-            compile_project(pf.to_string());
+            println!(
+                "{}{}{}",
+                "Building with ".blue(),
+                profile.name.bold().cyan(),
+                " profile".blue()
+            );
+            compile_project(
+                pf.to_string(),
+                &code,
+                &config,
+                profile,
+                &BuildOptions {
+                    payload_format,
+                    codec,
+                    obfuscate,
+                    embed,
+                    force,
+                },
+            );
         }
         Err(e) => {
             eprintln!(
@@ -139,3 +358,13 @@ fn validate_main_file(mf: &str) -> Result<(), String> {
 
     Ok(())
 }
+
+/// Rejects a flag that only changes how a build is written.
+fn build_only_flag(flag: &str, command: Option<&str>) -> ! {
+    eprintln!(
+        "{}{}",
+        format!("Error: {} only applies to builds, not ", flag).bold().red(),
+        command.unwrap_or("build").bold().red()
+    );
+    exit(-1);
+}
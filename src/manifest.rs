@@ -0,0 +1,199 @@
+use crate::config::{BuildProfile, CompilerConfig};
+use crate::datagen::BuildOptions;
+use bimble::bytecode::BYTECODE_VERSION;
+use bimble::payload::{PayloadFormat, PAYLOAD_VERSION};
+use bimble::resources::Resource;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+
+/// One artifact written by `compile_binary`.
+pub struct BuiltArtifact {
+    pub platform: &'static str,
+    pub base_vm: String,
+    /// The format `base_vm` reads, embedded in the artifact.
+    pub payload_format: PayloadFormat,
+    pub path: String,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn file_sha256(path: &str) -> Result<String, String> {
+    fs::read(path)
+        .map(|data| sha256_hex(&data))
+        .map_err(|e| format!("Unable to hash {}: {}", path, e))
+}
+
+/// File name of a VM template, so the manifest holds no absolute paths.
+fn template_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Writes `build/manifest.json` describing every input and output of the
+/// build. The manifest holds no timestamps or absolute paths, so identical
+/// inputs produce an identical manifest.
+pub fn write_manifest(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+    options: &BuildOptions,
+    resources: &[Resource],
+    artifacts: &[BuiltArtifact],
+) -> Result<(), String> {
+    let build_dir = format!("{}/build", project_path);
+
+    let defines: Map<String, Value> = config
+        .defines
+        .iter()
+        .map(|(k, v)| (k.clone(), json!(v)))
+        .collect();
+    let resources: Map<String, Value> = resources
+        .iter()
+        .map(|r| (r.name.clone(), json!(sha256_hex(&r.data))))
+        .collect();
+
+    let mut templates = Map::new();
+    let mut outputs = Map::new();
+    for artifact in artifacts {
+        templates.insert(
+            artifact.platform.to_string(),
+            json!({
+                "path": template_name(&artifact.base_vm),
+                "sha256": file_sha256(&artifact.base_vm)?,
+            }),
+        );
+
+        let data = fs::read(&artifact.path)
+            .map_err(|e| format!("Unable to hash {}: {}", artifact.path, e))?;
+        let relative = artifact
+            .path
+            .strip_prefix(&format!("{}/", build_dir))
+            .unwrap_or(&artifact.path);
+        let mut output = json!({
+            "path": relative,
+            "size": data.len(),
+            "sha256": sha256_hex(&data),
+        });
+        match artifact.payload_format {
+            PayloadFormat::Legacy => output["payload_format"] = json!("legacy"),
+            PayloadFormat::Bytecode => {
+                output["payload_format"] = json!(PAYLOAD_VERSION);
+                output["bytecode_version"] = json!(BYTECODE_VERSION);
+            }
+        }
+        outputs.insert(artifact.platform.to_string(), output);
+    }
+
+    let manifest = json!({
+        "name": config.name,
+        "version": config.version,
+        "profile": profile.name,
+        "compression": options.codec.name(),
+        "obfuscated": options.obfuscate,
+        "embed": options.embed.name(),
+        "defines": defines,
+        "inputs": {
+            "main.bb": sha256_hex(source_code.as_bytes()),
+            "cfg.bcf": file_sha256(&format!("{}/cfg.bcf", project_path))?,
+        },
+        "resources": resources,
+        "vm_templates": templates,
+        "artifacts": outputs,
+    });
+
+    let manifest_file = format!("{}/manifest.json", build_dir);
+    let json = serde_json::to_string_pretty(&manifest).unwrap() + "\n";
+    fs::write(&manifest_file, json).map_err(|e| format!("Unable to write {}: {}", manifest_file, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bimble::exe::EmbedMode;
+    use bimble::payload::Codec;
+    use std::env;
+
+    #[test]
+    fn manifest_lists_inputs_and_outputs() {
+        let dir = env::temp_dir().join(format!("bimble-manifest-{}", std::process::id()));
+        let project = dir.display().to_string();
+        fs::create_dir_all(dir.join("build/linux")).unwrap();
+        fs::write(dir.join("cfg.bcf"), "Name: app").unwrap();
+        fs::write(dir.join("vm-linux"), "vm").unwrap();
+        fs::write(dir.join("build/linux/app"), "artifact").unwrap();
+
+        let mut config = CompilerConfig::new();
+        config.name = "app".to_string();
+        config.set_define("PATH".to_string(), "\"C:\\tmp\\\"\"".to_string());
+        config.set_define("TAB".to_string(), "\"\t\u{1}\"".to_string());
+        let options = BuildOptions {
+            payload_format: None,
+            codec: Codec::Deflate,
+            obfuscate: false,
+            embed: EmbedMode::Section,
+            force: false,
+        };
+        let resources = [Resource {
+            name: "data/\u{7}bell.txt".to_string(),
+            data: b"hi".to_vec(),
+        }];
+        let artifacts = [BuiltArtifact {
+            platform: "linux",
+            base_vm: format!("{}/vm-linux", project),
+            payload_format: PayloadFormat::Bytecode,
+            path: format!("{}/build/linux/app", project),
+        }];
+
+        let written = write_manifest(
+            &project,
+            "echoln(1)",
+            &config,
+            &BuildProfile::release(),
+            &options,
+            &resources,
+            &artifacts,
+        );
+        let manifest = fs::read(dir.join("build/manifest.json"));
+        fs::remove_dir_all(&dir).unwrap();
+        written.unwrap();
+        let manifest: Value = serde_json::from_slice(&manifest.unwrap()).unwrap();
+
+        assert_eq!(manifest["name"], "app");
+        assert_eq!(manifest["profile"], "release");
+        assert_eq!(manifest["compression"], "deflate");
+        assert_eq!(manifest["embed"], "section");
+        assert_eq!(manifest["defines"]["PATH"], "\"C:\\tmp\\\"\"");
+        assert_eq!(manifest["defines"]["TAB"], "\"\t\u{1}\"");
+        assert_eq!(
+            manifest["inputs"]["main.bb"],
+            sha256_hex(b"echoln(1)").as_str()
+        );
+        assert_eq!(
+            manifest["resources"]["data/\u{7}bell.txt"],
+            sha256_hex(b"hi").as_str()
+        );
+        assert_eq!(
+            manifest["vm_templates"]["linux"],
+            json!({ "path": "vm-linux", "sha256": sha256_hex(b"vm") })
+        );
+        assert_eq!(
+            manifest["artifacts"]["linux"],
+            json!({
+                "path": "linux/app",
+                "size": 8,
+                "sha256": sha256_hex(b"artifact"),
+                "payload_format": PAYLOAD_VERSION,
+                "bytecode_version": BYTECODE_VERSION,
+            })
+        );
+    }
+}
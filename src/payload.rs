@@ -0,0 +1,379 @@
+use crate::exe::{find_section, SECTION_NAME};
+
+/// Marker at the start of every versioned payload.
+pub const PAYLOAD_MAGIC: &[u8; 4] = b"BBPL";
+/// Current payload format version. Version 1 is the headerless ASCII encoding
+/// and version 2 held the raw program text. Version 3 payloads carry a
+/// serialized bytecode program, optionally followed by a `BBRS` resource
+/// archive, and are stored behind a footer that records the codec.
+pub const PAYLOAD_VERSION: u8 = 3;
+
+/// Embedded in every runtime that reads the current payload version, so the
+/// compiler can tell a `bimble-vm` template from one of the original VM
+/// templates.
+pub const VM_MARKER: &[u8] = b"BIMBLE-VM:reads-payload-v3";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadFormat {
+    /// Eight ASCII '0'/'1' characters plus a '.' per source byte, as read by
+    /// the original VM templates.
+    Legacy,
+    /// Raw bytes of the bytecode program behind a `BBPL` header.
+    Bytecode,
+}
+
+impl PayloadFormat {
+    /// The only format VM template `vm` reads: `Bytecode` for a `bimble-vm` build,
+    /// `Legacy` for the original templates.
+    pub fn read_by(vm: &[u8]) -> Self {
+        if vm.windows(VM_MARKER.len()).any(|w| w == VM_MARKER) {
+            PayloadFormat::Bytecode
+        } else {
+            PayloadFormat::Legacy
+        }
+    }
+}
+
+/// Encodes a program (source text for `Legacy`, bytecode for `Bytecode`) for
+/// embedding into an artifact.
+///
+/// The layout is `BBPL`, the version byte, a flags byte (currently 0) and
+/// the program length as a little-endian u32, followed by the raw bytes. A
+/// resource archive, when present, is appended right after the program.
+pub fn encode_payload(program: &[u8], format: PayloadFormat) -> Vec<u8> {
+    match format {
+        PayloadFormat::Legacy => {
+            let mut binary_code = String::new();
+            for byte in program {
+                binary_code.push_str(format!("{:08b}", byte).trim());
+                binary_code.push('.');
+            }
+            binary_code.into_bytes()
+        }
+        PayloadFormat::Bytecode => {
+            let mut data = Vec::with_capacity(program.len() + 10);
+            data.extend_from_slice(PAYLOAD_MAGIC);
+            data.push(PAYLOAD_VERSION);
+            data.push(0);
+            data.extend_from_slice(&(program.len() as u32).to_le_bytes());
+            data.extend_from_slice(program);
+            data
+        }
+    }
+}
+
+/// Compression applied to the stored payload, recorded in the footer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None = 0,
+    /// Raw DEFLATE via the pure-Rust miniz_oxide.
+    Deflate = 1,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(Codec::None),
+            "deflate" => Ok(Codec::Deflate),
+            _ => Err(format!(
+                "Unknown compression codec '{}', expected 'none' or 'deflate'",
+                name
+            )),
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, String> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Deflate),
+            _ => Err(format!("Unknown compression codec id {}", id)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::None => data.to_vec(),
+            Codec::Deflate => miniz_oxide::deflate::compress_to_vec(data, 9),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Deflate => miniz_oxide::inflate::decompress_to_vec(data)
+                .map_err(|e| format!("Unable to inflate payload: {:?}", e.status)),
+        }
+    }
+}
+
+/// Length of the per-build key stored in front of an obfuscated payload.
+pub const KEY_LEN: usize = 16;
+
+/// XORs `data` with an xorshift128+ keystream seeded from `key`. Applying it
+/// twice with the same key restores the input.
+///
+/// This keeps the program out of reach of `strings` and casual inspection
+/// only. The key is stored inside the artifact, so anyone who knows this
+/// format can undo it: it is obfuscation, not a security guarantee.
+pub fn obfuscate(data: &[u8], key: &[u8; KEY_LEN]) -> Vec<u8> {
+    let mut s0 = u64::from_le_bytes(key[0..8].try_into().unwrap()) | 1;
+    let mut s1 = u64::from_le_bytes(key[8..16].try_into().unwrap());
+    let mut out = Vec::with_capacity(data.len());
+
+    for chunk in data.chunks(8) {
+        let (mut x, y) = (s0, s1);
+        s0 = y;
+        x ^= x << 23;
+        s1 = x ^ y ^ (x >> 17) ^ (y >> 26);
+        let stream = s1.wrapping_add(y).to_le_bytes();
+        out.extend(chunk.iter().zip(stream).map(|(b, k)| b ^ k));
+    }
+    out
+}
+
+/// Checks a `BBPL` payload header and splits what follows it into the program
+/// bytes and the resource archive (empty when the build has no resources).
+pub fn decode_payload(data: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if data.len() < 10 || &data[0..4] != PAYLOAD_MAGIC {
+        return Err("Payload does not start with the BBPL header".to_string());
+    }
+    if data[4] != PAYLOAD_VERSION {
+        return Err(format!(
+            "Unsupported payload version {} (expected {})",
+            data[4], PAYLOAD_VERSION
+        ));
+    }
+    let length = u32::from_le_bytes(data[6..10].try_into().unwrap()) as usize;
+    if data.len() - 10 < length {
+        return Err(format!(
+            "Payload header declares {} bytes but {} are present",
+            length,
+            data.len() - 10
+        ));
+    }
+    Ok(data[10..].split_at(length))
+}
+
+/// Marker closing every artifact built with a `BBPL` payload.
+pub const FOOTER_MAGIC: &[u8; 8] = b"BIMBLEFT";
+/// Size of the fixed footer appended after the payload.
+pub const FOOTER_LEN: usize = 32;
+
+/// Fixed-size trailer locating the payload inside an artifact.
+///
+/// Layout (little-endian): payload offset u64, payload length u64, CRC32 of
+/// the stored payload u32, payload version u8, codec u8, flags u8, one
+/// reserved byte, `BIMBLEFT`.
+///
+/// When `obfuscated` is set the stored payload starts with the `KEY_LEN` byte
+/// key, followed by the compressed payload run through `obfuscate`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Footer {
+    pub offset: u64,
+    pub length: u64,
+    pub crc32: u32,
+    pub version: u8,
+    pub codec: Codec,
+    pub obfuscated: bool,
+}
+
+/// Footer flag marking an obfuscated payload.
+const FLAG_OBFUSCATED: u8 = 1;
+
+impl Footer {
+    pub fn new(offset: u64, payload: &[u8], codec: Codec, obfuscated: bool) -> Self {
+        Self {
+            offset,
+            length: payload.len() as u64,
+            crc32: crc32fast::hash(payload),
+            version: PAYLOAD_VERSION,
+            codec,
+            obfuscated,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; FOOTER_LEN] {
+        let mut out = [0u8; FOOTER_LEN];
+        out[0..8].copy_from_slice(&self.offset.to_le_bytes());
+        out[8..16].copy_from_slice(&self.length.to_le_bytes());
+        out[16..20].copy_from_slice(&self.crc32.to_le_bytes());
+        out[20] = self.version;
+        out[21] = self.codec as u8;
+        if self.obfuscated {
+            out[22] |= FLAG_OBFUSCATED;
+        }
+        out[24..32].copy_from_slice(FOOTER_MAGIC);
+        out
+    }
+
+    /// Reads the footer from the end of a whole artifact.
+    pub fn read(artifact: &[u8]) -> Result<Self, String> {
+        if artifact.len() < FOOTER_LEN {
+            return Err("File is too small to contain a bimble footer".to_string());
+        }
+        let raw = &artifact[artifact.len() - FOOTER_LEN..];
+        if &raw[24..32] != FOOTER_MAGIC {
+            return Err("No bimble footer found (missing BIMBLEFT marker)".to_string());
+        }
+
+        let u64_at = |i: usize| u64::from_le_bytes(raw[i..i + 8].try_into().unwrap());
+        let footer = Self {
+            offset: u64_at(0),
+            length: u64_at(8),
+            crc32: u32::from_le_bytes(raw[16..20].try_into().unwrap()),
+            version: raw[20],
+            codec: Codec::from_id(raw[21])?,
+            obfuscated: raw[22] & FLAG_OBFUSCATED != 0,
+        };
+
+        let payload_end = footer.offset.checked_add(footer.length);
+        if payload_end != Some((artifact.len() - FOOTER_LEN) as u64) {
+            return Err(format!(
+                "Footer points outside the payload area (offset {}, length {})",
+                footer.offset, footer.length
+            ));
+        }
+        Ok(footer)
+    }
+
+    /// Returns the payload bytes after checking them against the stored CRC32
+    /// and undoing the obfuscation and compression.
+    pub fn payload(&self, artifact: &[u8]) -> Result<Vec<u8>, String> {
+        let payload = &artifact[self.offset as usize..(self.offset + self.length) as usize];
+        let crc32 = crc32fast::hash(payload);
+        if crc32 != self.crc32 {
+            return Err(format!(
+                "Checksum mismatch: footer has {:08x}, payload hashes to {:08x}",
+                self.crc32, crc32
+            ));
+        }
+        if self.obfuscated {
+            if payload.len() < KEY_LEN {
+                return Err("Obfuscated payload is missing its key".to_string());
+            }
+            let key: [u8; KEY_LEN] = payload[..KEY_LEN].try_into().unwrap();
+            return self.codec.decompress(&obfuscate(&payload[KEY_LEN..], &key));
+        }
+        self.codec.decompress(payload)
+    }
+}
+
+/// A payload found in an artifact, with the region of the file it was read
+/// from.
+pub struct Located {
+    pub footer: Footer,
+    pub payload: Vec<u8>,
+    /// Name of the section holding the payload, or `None` when appended.
+    pub section: Option<&'static str>,
+}
+
+/// Finds the payload of an artifact, preferring a `.bimble` section over a
+/// footer at the end of the file.
+pub fn locate_payload(data: &[u8]) -> Result<Located, String> {
+    let (region, section) = match find_section(data, SECTION_NAME) {
+        Some(region) => (region, Some(SECTION_NAME)),
+        None => (data, None),
+    };
+    let footer = Footer::read(region)?;
+    let payload = footer.payload(region)?;
+    Ok(Located {
+        footer,
+        payload,
+        section,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An artifact made of a stand-in executable, the stored payload and
+    /// its footer.
+    fn artifact(payload: &[u8], codec: Codec, obfuscated: bool) -> Vec<u8> {
+        let mut data = b"stand-in executable".to_vec();
+        let mut stored = codec.compress(payload);
+        if obfuscated {
+            let key = [7; KEY_LEN];
+            stored = [key.as_slice(), &obfuscate(&stored, &key)].concat();
+        }
+        let footer = Footer::new(data.len() as u64, &stored, codec, obfuscated);
+        data.extend_from_slice(&stored);
+        data.extend_from_slice(&footer.to_bytes());
+        data
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        let payload = encode_payload(b"bytecode", PayloadFormat::Bytecode);
+        for codec in [Codec::None, Codec::Deflate] {
+            for obfuscated in [false, true] {
+                let located = locate_payload(&artifact(&payload, codec, obfuscated)).unwrap();
+                assert_eq!(located.payload, payload);
+                assert_eq!(located.footer.codec, codec);
+                assert_eq!(located.footer.obfuscated, obfuscated);
+                assert_eq!(located.section, None);
+            }
+        }
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let payload = encode_payload(b"bytecode", PayloadFormat::Bytecode);
+        let mut data = artifact(&payload, Codec::None, false);
+        let at = data.len() - FOOTER_LEN - 1;
+        data[at] ^= 1;
+        let e = locate_payload(&data).err().unwrap();
+        assert!(e.starts_with("Checksum mismatch"), "{}", e);
+
+        // A footer whose region does not end where the footer starts
+        let mut data = artifact(&payload, Codec::None, false);
+        data.insert(0, b'!');
+        let e = locate_payload(&data).err().unwrap();
+        assert!(e.starts_with("Footer points outside"), "{}", e);
+
+        let data = artifact(&payload, Codec::None, false);
+        let e = Footer::read(&data[..data.len() - 1]).err().unwrap();
+        assert!(e.starts_with("No bimble footer"), "{}", e);
+    }
+
+    #[test]
+    fn payload_splits_program_and_archive() {
+        let mut payload = encode_payload(b"program", PayloadFormat::Bytecode);
+        payload.extend_from_slice(b"archive");
+        assert_eq!(
+            decode_payload(&payload).unwrap(),
+            (b"program".as_slice(), b"archive".as_slice())
+        );
+
+        payload[4] = PAYLOAD_VERSION + 1;
+        assert!(decode_payload(&payload).is_err());
+        payload[4] = 2;
+        assert!(decode_payload(&payload).is_err());
+        assert!(decode_payload(b"BBPL").is_err());
+    }
+
+    #[test]
+    fn legacy_payload_is_ascii_bits() {
+        assert_eq!(
+            encode_payload(b"A\n", PayloadFormat::Legacy),
+            b"01000001.00001010."
+        );
+    }
+
+    #[test]
+    fn format_follows_the_vm_marker() {
+        let vm = [b"head".as_slice(), VM_MARKER, b"tail"].concat();
+        assert_eq!(PayloadFormat::read_by(&vm), PayloadFormat::Bytecode);
+        assert_eq!(
+            PayloadFormat::read_by(b"old template"),
+            PayloadFormat::Legacy
+        );
+    }
+}
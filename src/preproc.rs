@@ -0,0 +1,192 @@
+use colored::Colorize;
+use std::process::exit;
+
+/// Every platform `compile_project` produces an artifact for.
+pub const TARGETS: [&str; 2] = ["linux", "windows"];
+
+pub struct PreprocError {
+    pub index: usize,
+    pub line: String,
+    pub msg: String,
+}
+
+/// Like `filter_target`, but reports the error the way `check` does and exits.
+pub fn filter_or_exit(code: &str, target: &str) -> Vec<String> {
+    match filter_target(code, target) {
+        Ok(lines) => lines,
+        Err(e) => {
+            eprintln!(
+                "{}{}{}{}{}",
+                "Error: ".red(),
+                e.msg.red(),
+                " at code (".red(),
+                e.index.to_string().bold().red(),
+                format!("): {}", e.line).red().bold()
+            );
+            exit(-1);
+        }
+    }
+}
+
+/// Resolves `#if <target>` / `#else` / `#end` blocks for one target.
+///
+/// `#` also starts a comment, so only `#if` followed by a known target (or
+/// its negation) and `#else`/`#end` alone on their line are directives; any
+/// other comment such as `#if we have time` or `#end of setup` is kept as is.
+/// Directive lines and lines belonging to inactive branches are replaced with
+/// empty lines so that line numbers keep matching main.bb.
+pub fn filter_target(code: &str, target: &str) -> Result<Vec<String>, PreprocError> {
+    let mut out = Vec::new();
+    // Each open block records whether its current branch is active
+    let mut blocks: Vec<(bool, bool, usize)> = Vec::new();
+
+    for (index, line) in code.lines().enumerate() {
+        let index = index + 1;
+        let cd = line.trim();
+        let err = |msg: String| PreprocError {
+            index,
+            line: cd.to_string(),
+            msg,
+        };
+
+        if let Some((negated, name)) = condition(cd) {
+            let parent = blocks.last().is_none_or(|b| b.0);
+            blocks.push((parent && ((name == target) != negated), false, index));
+            out.push(String::new());
+        } else if cd == "#else" {
+            let parent = blocks.len() < 2 || blocks[blocks.len() - 2].0;
+            match blocks.last_mut() {
+                Some(block) if !block.1 => {
+                    block.0 = parent && !block.0;
+                    block.1 = true;
+                }
+                Some(_) => return Err(err("Duplicate #else".to_string())),
+                None => return Err(err("#else without matching #if".to_string())),
+            }
+            out.push(String::new());
+        } else if cd == "#end" {
+            if blocks.pop().is_none() {
+                return Err(err("#end without matching #if".to_string()));
+            }
+            out.push(String::new());
+        } else if blocks.last().is_none_or(|b| b.0) {
+            out.push(line.to_string());
+        } else {
+            out.push(String::new());
+        }
+    }
+
+    if let Some((_, _, index)) = blocks.last() {
+        return Err(PreprocError {
+            index: *index,
+            line: code.lines().nth(index - 1).unwrap_or("").trim().to_string(),
+            msg: "Unterminated #if block, expected #end".to_string(),
+        });
+    }
+
+    Ok(out)
+}
+
+/// The target of an `#if` directive line and whether it is negated, or
+/// `None` when the line is an ordinary comment.
+fn condition(line: &str) -> Option<(bool, &str)> {
+    let cond = line.strip_prefix("#if ")?.trim();
+    let (negated, name) = match cond.strip_prefix('!') {
+        Some(name) => (true, name.trim()),
+        None => (false, cond),
+    };
+    TARGETS.contains(&name).then_some((negated, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NESTED: &str = "\
+a
+#if linux
+b
+#if !windows
+c
+#else
+d
+#end
+#else
+e
+#if windows
+f
+#end
+#end
+g";
+
+    /// The non-empty lines `filter_target` keeps.
+    fn kept(code: &str, target: &str) -> Vec<String> {
+        let lines = filter_target(code, target).ok().unwrap();
+        assert_eq!(lines.len(), code.lines().count());
+        lines.into_iter().filter(|l| !l.is_empty()).collect()
+    }
+
+    #[test]
+    fn nested_blocks() {
+        assert_eq!(kept(NESTED, "linux"), ["a", "b", "c", "g"]);
+        assert_eq!(kept(NESTED, "windows"), ["a", "e", "f", "g"]);
+    }
+
+    #[test]
+    fn lines_keep_their_numbers() {
+        let lines = filter_target(NESTED, "windows").ok().unwrap();
+        assert_eq!(lines[9], "e");
+        assert_eq!(lines[11], "f");
+    }
+
+    #[test]
+    fn unbalanced_directives() {
+        let error = |code: &str| {
+            let e = filter_target(code, "linux").err().unwrap();
+            (e.index, e.msg)
+        };
+        assert_eq!(error("#if linux\na").0, 1);
+        assert_eq!(
+            error("#if linux\n#else\n#else\n#end"),
+            (3, "Duplicate #else".to_string())
+        );
+        assert_eq!(
+            error("a\n#end"),
+            (2, "#end without matching #if".to_string())
+        );
+        assert_eq!(
+            error("#else"),
+            (1, "#else without matching #if".to_string())
+        );
+        // Not a directive, so the #end has no #if
+        assert_eq!(
+            error("#if macos\n#end"),
+            (2, "#end without matching #if".to_string())
+        );
+    }
+
+    #[test]
+    fn comments_are_not_directives() {
+        let code = "\
+#if we have time
+a
+#else we fall back
+#end of setup
+#endif
+#if linux
+#ifdef
+b
+#end";
+        assert_eq!(
+            kept(code, "windows"),
+            [
+                "#if we have time",
+                "a",
+                "#else we fall back",
+                "#end of setup",
+                "#endif"
+            ]
+        );
+        assert_eq!(kept(code, "linux").len(), 7);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::config::{BuildProfile, CompilerConfig};
+use crate::datagen::{load_host_program, lower_or_exit};
+use bimble::vm::{Status, Vm};
+use colored::Colorize;
+use std::collections::HashMap;
+use std::fs;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+/// Number of lines listed under "Hot lines".
+const HOT_LINES: usize = 10;
+
+#[derive(Default)]
+struct FunctionStats {
+    calls: u64,
+    /// Time spent in the function and everything it called.
+    inclusive: Duration,
+    /// Time spent in the function's own instructions.
+    exclusive: Duration,
+}
+
+#[derive(Default)]
+struct LineStats {
+    /// How often the statement on this line started.
+    hits: u64,
+    time: Duration,
+}
+
+/// Timings collected while running a program one instruction at a time.
+/// Functions are numbered like `Program::fns`, shifted by one so that 0 is
+/// the top-level code, reported as `main`.
+struct Timings {
+    names: Vec<String>,
+    functions: Vec<FunctionStats>,
+    lines: HashMap<u32, LineStats>,
+    /// Every distinct call stack as a tree: node 0 is `main`, each other node
+    /// is a (parent, function) pair with the exclusive time of that stack.
+    stacks: Vec<StackNode>,
+    instructions: u64,
+    total: Duration,
+}
+
+struct StackNode {
+    parent: usize,
+    function: usize,
+    time: Duration,
+}
+
+impl Timings {
+    fn record(vm: &mut Vm) -> (Self, Result<(), String>) {
+        let mut names = vec!["main".to_string()];
+        names.extend(vm.program.fns.iter().map(|f| f.name.clone()));
+        let mut profile = Self {
+            functions: (0..names.len()).map(|_| FunctionStats::default()).collect(),
+            names,
+            lines: HashMap::new(),
+            stacks: vec![StackNode {
+                parent: 0,
+                function: 0,
+                time: Duration::ZERO,
+            }],
+            instructions: 0,
+            total: Duration::ZERO,
+        };
+        profile.functions[0].calls = 1;
+
+        // Stack node of every active frame, kept in step with `vm.frames`
+        let mut path = vec![0];
+        let mut children: HashMap<(usize, usize), usize> = HashMap::new();
+        // Active frames per function and when the outermost one started, so
+        // a recursive function is only charged once
+        let mut active = vec![0u32; profile.names.len()];
+        let mut entered = vec![Duration::ZERO; profile.names.len()];
+        active[0] = 1;
+        // Sum of the time spent in instructions so far
+        let mut clock = Duration::ZERO;
+
+        let started = Instant::now();
+        let result = loop {
+            let pc = vm.pc;
+            let depth = vm.frames.len();
+            let node = *path.last().unwrap();
+            if let Some(line) = vm.program.line_starting_at(pc).filter(|l| *l > 0) {
+                profile.lines.entry(line).or_default().hits += 1;
+            }
+
+            let start = Instant::now();
+            let status = vm.step();
+            let elapsed = start.elapsed();
+            profile.instructions += 1;
+            clock += elapsed;
+
+            if let Some(line) = vm.program.line_of(pc).filter(|l| *l > 0) {
+                profile.lines.entry(line).or_default().time += elapsed;
+            }
+            profile.functions[profile.stacks[node].function].exclusive += elapsed;
+            profile.stacks[node].time += elapsed;
+
+            if vm.frames.len() > depth {
+                let f = vm.frames.last().unwrap().function + 1;
+                profile.functions[f].calls += 1;
+                let stacks = &mut profile.stacks;
+                let child = *children.entry((node, f)).or_insert_with(|| {
+                    stacks.push(StackNode {
+                        parent: node,
+                        function: f,
+                        time: Duration::ZERO,
+                    });
+                    stacks.len() - 1
+                });
+                path.push(child);
+                if active[f] == 0 {
+                    entered[f] = clock;
+                }
+                active[f] += 1;
+            } else if vm.frames.len() < depth {
+                let f = profile.stacks[path.pop().unwrap()].function;
+                active[f] -= 1;
+                if active[f] == 0 {
+                    profile.functions[f].inclusive += clock - entered[f];
+                }
+            }
+
+            match status {
+                Ok(Status::Running) => (),
+                Ok(Status::Halted) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        // Functions still running when the program stopped
+        for (f, stats) in profile.functions.iter_mut().enumerate() {
+            if active[f] > 0 {
+                stats.inclusive += clock - entered[f];
+            }
+        }
+        profile.total = started.elapsed();
+        (profile, result)
+    }
+
+    fn print_summary(&self, source: &[String]) {
+        eprintln!(
+            "{}",
+            format!(
+                "Profile: {} instructions in {}",
+                self.instructions,
+                ms(self.total)
+            )
+            .blue()
+        );
+
+        let mut functions: Vec<_> = self
+            .names
+            .iter()
+            .zip(&self.functions)
+            .filter(|(_, stats)| stats.calls > 0)
+            .collect();
+        functions.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.inclusive));
+        eprintln!(
+            "  {:<20} {:>8} {:>12} {:>12}",
+            "function", "calls", "inclusive", "exclusive"
+        );
+        for (name, stats) in functions {
+            eprintln!(
+                "  {:<20} {:>8} {:>12} {:>12}",
+                name,
+                stats.calls,
+                ms(stats.inclusive),
+                ms(stats.exclusive)
+            );
+        }
+
+        let mut lines: Vec<_> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        eprintln!("{}", "Hot lines:".blue());
+        for (line, stats) in lines.into_iter().take(HOT_LINES) {
+            let text = source.get(*line as usize - 1).map_or("", |l| l.trim());
+            eprintln!(
+                "  {:>12} {:>8} hits  code ({}): {}",
+                ms(stats.time),
+                stats.hits,
+                line,
+                text
+            );
+        }
+    }
+
+    /// One `main;f;g <microseconds>` line per call stack, the folded format
+    /// read by flamegraph.pl and inferno.
+    fn folded(&self) -> String {
+        let mut stacks: Vec<(String, u128)> = self
+            .stacks
+            .iter()
+            .map(|node| {
+                let mut names = vec![self.names[node.function].as_str()];
+                let mut at = node;
+                while at.function != 0 {
+                    at = &self.stacks[at.parent];
+                    names.push(self.names[at.function].as_str());
+                }
+                names.reverse();
+                (names.join(";"), node.time.as_micros())
+            })
+            .collect();
+        stacks.sort();
+        stacks
+            .iter()
+            .map(|(stack, micros)| format!("{} {}\n", stack, micros))
+            .collect()
+    }
+}
+
+fn ms(time: Duration) -> String {
+    format!("{:.3}ms", time.as_secs_f64() * 1000.0)
+}
+
+/// Runs `bimble run --profile`: executes main.bb on the VM, then prints a
+/// summary to stderr and writes folded stacks to build/profile.folded.
+pub fn profile_project(
+    project_path: &str,
+    source_code: &str,
+    config: &CompilerConfig,
+    profile: &BuildProfile,
+) {
+    let host = load_host_program(project_path, source_code, config);
+    // Hot lines need the line table whatever the profile says
+    let profile = BuildProfile {
+        line_table: true,
+        ..profile.clone()
+    };
+    let mut program = lower_or_exit(&host.checked, &host.source, &profile);
+    program.limits = config.limits;
+    program.capabilities = host.capabilities;
+    let mut vm = Vm::new(program, host.resources);
+    let (timings, result) = Timings::record(&mut vm);
+
+    timings.print_summary(&host.source);
+    let build_dir = format!("{}/build", project_path);
+    let folded = format!("{}/profile.folded", build_dir);
+    match fs::create_dir_all(&build_dir).and_then(|_| fs::write(&folded, timings.folded())) {
+        Ok(()) => eprintln!(
+            "{}{}",
+            "Wrote folded stacks -> ".blue(),
+            folded.bold().cyan()
+        ),
+        Err(e) => eprintln!(
+            "{}{}{}",
+            "Error: Unable to write ".red(),
+            folded.bold().red(),
+            format!(": {}", e).red()
+        ),
+    }
+
+    if let Err(msg) = result {
+        let err = vm.error(msg).quote(&host.source);
+        eprintln!("{}{}", "Error: ".red(), err.to_string().bold().red());
+        exit(err.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bimble::caps::Capabilities;
+    use bimble::check::{check_program, Checker};
+    use bimble::codegen::lower;
+
+    fn profile(source: &str) -> Timings {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let mut checker = Checker::new(&[], &Capabilities::default());
+        let checked = check_program(&mut checker, &lines, &[]).unwrap();
+        let program = lower(&checked, true).ok().unwrap();
+        let (timings, result) = Timings::record(&mut Vm::new(program, Vec::new()));
+        assert!(result.is_ok());
+        timings
+    }
+
+    /// The stacks of the folded output, without their times.
+    fn folded_stacks(timings: &Timings) -> Vec<String> {
+        timings
+            .folded()
+            .lines()
+            .map(|line| {
+                let (stack, micros) = line.rsplit_once(' ').unwrap();
+                assert!(micros.parse::<u128>().is_ok(), "{}", line);
+                stack.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeated_calls_fold_into_one_stack() {
+        let timings = profile("f()\nf()\nON f(){\n    may x = 1\n}");
+        assert_eq!(folded_stacks(&timings), ["main", "main;f"]);
+        assert_eq!(timings.functions[1].calls, 2);
+        assert_eq!(
+            timings.functions[0].inclusive,
+            timings.stacks.iter().map(|n| n.time).sum()
+        );
+    }
+
+    #[test]
+    fn nested_calls_get_their_own_stacks() {
+        let source = "\
+f()
+g()
+ON f(){
+    g()
+}
+ON g(){
+    may x = 1
+}";
+        let timings = profile(source);
+        assert_eq!(
+            folded_stacks(&timings),
+            ["main", "main;f", "main;f;g", "main;g"]
+        );
+        assert_eq!(timings.functions[2].calls, 2);
+        assert!(timings.functions[1].inclusive >= timings.functions[1].exclusive);
+    }
+}
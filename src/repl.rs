@@ -0,0 +1,314 @@
+use crate::config::CompilerConfig;
+use crate::interp::Interpreter;
+use bimble::caps::Capabilities;
+use bimble::check::{check_program, CheckedLine, Checker, Stmt};
+use bimble::preproc::filter_target;
+use colored::Colorize;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const HISTORY_FILE: &str = ".bimble_history";
+
+/// Checker and interpreter state shared by every entry of the session.
+struct Session {
+    checker: Checker,
+    interp: Interpreter,
+    /// Statements of an `ON f(){` entry still waiting for its `}`.
+    pending: Vec<CheckedLine>,
+    /// Every line entered so far; an entry's line number is its position here.
+    entered: Vec<String>,
+}
+
+/// Runs `bimble repl` until end of input or `:quit`. With a project, its
+/// `[limits]` apply to each entry on its own, and its `[capabilities]` and
+/// defines apply as they do for `bimble run`.
+pub fn repl(project: Option<(&str, &CompilerConfig)>) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("{}{}", "Error: ".bold().red(), e.to_string().bold().red());
+            return;
+        }
+    };
+    let history = history_file();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    let mut session = Session::new(project);
+    println!(
+        "{}",
+        "bimble repl - type :help for commands, :quit to leave".blue()
+    );
+
+    loop {
+        let prompt = if session.pending.is_empty() {
+            "bb> "
+        } else {
+            "... "
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                // Ctrl-C abandons an unfinished function definition
+                session.pending.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}{}", "Error: ".bold().red(), e.to_string().bold().red());
+                break;
+            }
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        match line.trim().split_once(' ').unwrap_or((line.trim(), "")) {
+            (":quit" | ":q", _) => break,
+            (":help", _) => help(),
+            (":vars", _) => session.vars(),
+            (":load", path) if !path.trim().is_empty() => session.load(path.trim()),
+            (":load", _) => report("Usage: :load <file>"),
+            (command, _) if command.starts_with(':') => {
+                report(&format!("Unknown command '{}', see :help", command))
+            }
+            _ => session.entry(&line),
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+}
+
+impl Session {
+    fn new(project: Option<(&str, &CompilerConfig)>) -> Self {
+        let Some((project_path, config)) = project else {
+            // Code typed at the repl is the user's own, so builtins are unrestricted
+            return Self {
+                checker: Checker::new(&[], &Capabilities::all()),
+                interp: Interpreter::new(Vec::new(), Capabilities::all()),
+                pending: Vec::new(),
+                entered: Vec::new(),
+            };
+        };
+
+        let capabilities = config.capabilities.relative_to(Path::new(project_path));
+        let mut session = Self {
+            checker: Checker::new(&[], &config.capabilities),
+            interp: Interpreter::new(Vec::new(), capabilities).with_limits(config.limits),
+            pending: Vec::new(),
+            entered: Vec::new(),
+        };
+        // Defines are declared before the first entry, as before main.bb
+        let defined = check_program(&mut session.checker, &[], &config.defines)
+            .and_then(|checked| session.interp.run(&checked).map_err(|e| e.msg));
+        if let Err(e) = defined {
+            report(&e);
+        }
+        session
+    }
+
+    /// Checks one entered line and runs it, or buffers it while a function
+    /// body is open.
+    fn entry(&mut self, line: &str) {
+        self.entered.push(line.to_string());
+        let index = self.entered.len();
+        let stmt = match self.checker.line(index, line) {
+            Ok(Some(stmt)) => stmt,
+            Ok(None) => return,
+            Err(e) => return report_at(&e, index, line),
+        };
+
+        let opens_body = matches!(stmt, Stmt::Fn { has_body: true, .. });
+        let closes_body = matches!(stmt, Stmt::End);
+        let in_body = !self.pending.is_empty();
+        if in_body && opens_body {
+            return report_at("Nested function definitions are not supported", index, line);
+        }
+        if !in_body && closes_body {
+            return report_at("Unexpected '}' outside of a function body", index, line);
+        }
+
+        self.pending.push(CheckedLine { index, stmt });
+        if opens_body || in_body && !closes_body {
+            return;
+        }
+
+        // Undefined calls are reported by the interpreter when they run
+        self.checker.clear_calls();
+        let checked = std::mem::take(&mut self.pending);
+        // Runtime errors, runaway recursion included, end the entry only
+        if let Err(e) = self.interp.run(&checked) {
+            report_at(&e.msg, e.index, self.line(e.index));
+        }
+    }
+
+    /// Checks and runs a whole file, like `bimble run --interp` would. A file
+    /// that fails to check leaves the session's declarations untouched.
+    fn load(&mut self, path: &str) {
+        let code = match fs::read_to_string(path) {
+            Ok(code) => code,
+            Err(e) => return report(&format!("Unable to read {}: {}", path, e)),
+        };
+        let platform = if cfg!(windows) { "windows" } else { "linux" };
+        let program = match filter_target(&code, platform) {
+            Ok(program) => program,
+            Err(e) => return report_at(&e.msg, e.index, &e.line),
+        };
+
+        // File lines are numbered after the lines entered so far
+        let base = self.entered.len();
+        self.entered.extend(program.iter().cloned());
+        let mut checker = self.checker.clone();
+        let mut checked = Vec::new();
+        for (i, line) in program.iter().enumerate() {
+            match checker.line(base + i + 1, line) {
+                Ok(Some(stmt)) => checked.push(CheckedLine {
+                    index: base + i + 1,
+                    stmt,
+                }),
+                Ok(None) => (),
+                Err(e) => return report_at(&e, base + i + 1, line),
+            }
+        }
+        if let Some(call) = checker.undefined_calls().first() {
+            return report(&format!("Undefined function call '{}' in {}", call, path));
+        }
+        checker.clear_calls();
+        self.checker = checker;
+        if let Err(e) = self.interp.run(&checked) {
+            report_at(&e.msg, e.index, self.line(e.index));
+        }
+    }
+
+    /// Text of line `index`, as numbered in diagnostics.
+    fn line(&self, index: usize) -> &str {
+        index
+            .checked_sub(1)
+            .and_then(|i| self.entered.get(i))
+            .map_or("", |l| l.as_str())
+    }
+
+    fn vars(&self) {
+        let mut vars: Vec<_> = self.interp.vars.iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, (value, vt)) in vars {
+            println!("  {} = {} ({:?})", name.cyan(), value, vt);
+        }
+    }
+}
+
+fn help() {
+    println!("  may x = 5        declare a variable");
+    println!("  ON f(){{ ... }}    define a function over several lines");
+    println!("  f()              call a function");
+    println!("  :load <file>     check and run a file");
+    println!("  :vars            list variables with their values and types");
+    println!("  :quit            leave the repl");
+}
+
+fn report(msg: &str) {
+    eprintln!("{}{}", "Error: ".red(), msg.red());
+}
+
+fn report_at(msg: &str, index: usize, line: &str) {
+    eprintln!(
+        "{}{}{}{}{}",
+        "Error: ".red(),
+        msg.red(),
+        " at code (".red(),
+        index.to_string().bold().red(),
+        format!("): {}", line.trim()).red().bold()
+    );
+}
+
+fn history_file() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursion_error_keeps_the_session() {
+        let mut session = Session::new(None);
+        for line in ["ON f(){", "f()", "}", "f()", "may x = 5"] {
+            session.entry(line);
+        }
+        assert!(session.interp.fns.contains_key("f"));
+        assert!(session.interp.vars.contains_key("x"));
+    }
+
+    #[test]
+    fn failed_loads_declare_nothing() {
+        let dir = env::temp_dir().join(format!("bimble-repl-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, code: &str| {
+            let path = dir.join(name);
+            fs::write(&path, code).unwrap();
+            path.display().to_string()
+        };
+        let (bad, undefined, good) = (
+            file("bad.bb", "ON g(){\n}\nmay y = 1\necholn(missing)"),
+            file("undefined.bb", "ON g(){\n}\nh()"),
+            file("good.bb", "ON g(){\n}\nmay y = 1"),
+        );
+
+        let mut session = Session::new(None);
+        // Whether `g` is known to the session's checker
+        let knows_g = |session: &mut Session| {
+            session.checker.line(0, "g()").unwrap();
+            let known = session.checker.undefined_calls().is_empty();
+            session.checker.clear_calls();
+            known
+        };
+        session.load(&bad);
+        session.load(&undefined);
+        let after_failures = knows_g(&mut session);
+        session.load(&good);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!after_failures);
+        assert!(knows_g(&mut session));
+        assert!(session.interp.vars.contains_key("y"));
+        assert_eq!(session.entered.len(), 10);
+    }
+
+    #[test]
+    fn projects_keep_their_capabilities() {
+        let dir = env::temp_dir().join(format!("bimble-repl-caps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("main.bb");
+        let notes = dir.join("notes.txt").display().to_string();
+        let code = format!("may x = 1\nmay notes = read(\"{}\")", notes);
+        fs::write(&file, code).unwrap();
+        let (project, file) = (dir.display().to_string(), file.display().to_string());
+
+        let mut config = CompilerConfig::new();
+        config.set_define("MODE".to_string(), "\"repl\"".to_string());
+        let mut session = Session::new(Some((&project, &config)));
+        session.load(&file);
+        let undeclared = session.interp.vars.contains_key("x");
+
+        config.capabilities.read.push(".".to_string());
+        fs::write(&notes, "hi").unwrap();
+        let mut declared = Session::new(Some((&project, &config)));
+        declared.load(&file);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!undeclared);
+        assert!(session.interp.vars.contains_key("MODE"));
+        assert_eq!(
+            declared.interp.vars.get("notes").map(|v| v.0.to_string()),
+            Some("hi".to_string())
+        );
+    }
+}
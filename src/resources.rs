@@ -0,0 +1,244 @@
+use std::{fs, path::Path};
+
+/// Marker at the start of the resource archive appended to the program.
+pub const ARCHIVE_MAGIC: &[u8; 4] = b"BBRS";
+
+/// A file packed into the artifact, named by its '/'-separated path relative
+/// to the project folder.
+pub struct Resource {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Expands the `Resources` patterns relative to the project folder. Patterns
+/// may use `*` and `?` in any path segment; every pattern has to match at
+/// least one file.
+pub fn collect_resources(project_path: &str, patterns: &[String]) -> Result<Vec<Resource>, String> {
+    let mut resources: Vec<Resource> = Vec::new();
+
+    for pattern in patterns {
+        let segments: Vec<&str> = pattern
+            .split(['/', '\\'])
+            .filter(|s| !s.is_empty())
+            .collect();
+        if segments.iter().any(|s| *s == ".." || *s == ".") {
+            return Err(format!(
+                "Resource pattern '{}' must stay inside the project folder",
+                pattern
+            ));
+        }
+
+        let mut matches = Vec::new();
+        expand(Path::new(project_path), "", &segments, &mut matches)
+            .map_err(|e| format!("Unable to read resources for '{}': {}", pattern, e))?;
+        if matches.is_empty() {
+            return Err(format!("Resource pattern '{}' matched no files", pattern));
+        }
+
+        matches.sort();
+        for name in matches {
+            if resources.iter().any(|r| r.name == name) {
+                continue;
+            }
+            let data = fs::read(Path::new(project_path).join(&name))
+                .map_err(|e| format!("Unable to read resource '{}': {}", name, e))?;
+            resources.push(Resource { name, data });
+        }
+    }
+
+    Ok(resources)
+}
+
+fn expand(
+    dir: &Path,
+    prefix: &str,
+    segments: &[&str],
+    out: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let Some((segment, rest)) = segments.split_first() else {
+        return Ok(());
+    };
+
+    let mut candidates = Vec::new();
+    if segment.contains(['*', '?']) {
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if glob_match(segment, &name) {
+                candidates.push(name);
+            }
+        }
+    } else if dir.join(segment).exists() {
+        candidates.push(segment.to_string());
+    }
+
+    for name in candidates {
+        let path = dir.join(&name);
+        let joined = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        if rest.is_empty() {
+            if path.is_file() {
+                out.push(joined);
+            }
+        } else if path.is_dir() {
+            expand(&path, &joined, rest, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Matches a single path segment against a pattern with `*` and `?`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Serializes resources as `BBRS`, a u32 count, then a u32-length-prefixed
+/// name and u32-length-prefixed data per file, all little-endian.
+pub fn pack(resources: &[Resource]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(ARCHIVE_MAGIC);
+    out.extend_from_slice(&(resources.len() as u32).to_le_bytes());
+    for resource in resources {
+        out.extend_from_slice(&(resource.name.len() as u32).to_le_bytes());
+        out.extend_from_slice(resource.name.as_bytes());
+        out.extend_from_slice(&(resource.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&resource.data);
+    }
+    out
+}
+
+pub fn unpack(data: &[u8]) -> Result<Vec<Resource>, String> {
+    if data.len() < 8 || &data[0..4] != ARCHIVE_MAGIC {
+        return Err("Resource archive does not start with the BBRS header".to_string());
+    }
+    let truncated = || "Resource archive is truncated".to_string();
+    let mut pos = 8;
+    let mut take = |n: usize| -> Result<&[u8], String> {
+        let bytes = data.get(pos..pos + n).ok_or_else(truncated)?;
+        pos += n;
+        Ok(bytes)
+    };
+
+    let count = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let mut resources = Vec::new();
+    for _ in 0..count {
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let name = String::from_utf8(take(len)?.to_vec())
+            .map_err(|_| "Invalid UTF-8 in resource name".to_string())?;
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let data = take(len)?.to_vec();
+        resources.push(Resource { name, data });
+    }
+    Ok(resources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn names(resources: &[Resource]) -> Vec<&str> {
+        resources.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let resources = vec![
+            Resource {
+                name: "data/a.txt".to_string(),
+                data: b"alpha".to_vec(),
+            },
+            Resource {
+                name: "empty".to_string(),
+                data: Vec::new(),
+            },
+        ];
+        let archive = pack(&resources);
+        assert!(archive.starts_with(ARCHIVE_MAGIC));
+        let back = unpack(&archive).unwrap();
+        assert_eq!(names(&back), ["data/a.txt", "empty"]);
+        assert_eq!(back[0].data, b"alpha");
+        assert!(back[1].data.is_empty());
+    }
+
+    #[test]
+    fn damaged_archives_are_rejected() {
+        let archive = pack(&[Resource {
+            name: "a".to_string(),
+            data: b"alpha".to_vec(),
+        }]);
+        assert_eq!(
+            unpack(&archive[..archive.len() - 1]).err().unwrap(),
+            "Resource archive is truncated"
+        );
+        assert!(unpack(&archive[1..]).is_err());
+        assert!(unpack(b"BBRS").is_err());
+    }
+
+    #[test]
+    fn glob_segments() {
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("file?.bin", "file1.bin"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("*.txt", "notes.txt.bak"));
+        assert!(!glob_match("file?.bin", "file.bin"));
+    }
+
+    #[test]
+    fn patterns_expand_inside_the_project() {
+        let dir = env::temp_dir().join(format!("bimble-resources-{}", std::process::id()));
+        fs::create_dir_all(dir.join("assets/img")).unwrap();
+        for file in [
+            "assets/a.txt",
+            "assets/b.txt",
+            "assets/c.bin",
+            "assets/img/logo.png",
+        ] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let project = dir.to_str().unwrap();
+        let collect = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            collect_resources(project, &patterns)
+        };
+
+        let resources = collect(&["assets/*.txt", "assets/a.txt", "*/img/*"]).unwrap();
+        assert_eq!(
+            names(&resources),
+            ["assets/a.txt", "assets/b.txt", "assets/img/logo.png"]
+        );
+        assert_eq!(resources[0].data, b"assets/a.txt");
+        assert!(collect(&["assets/*.md"])
+            .err()
+            .unwrap()
+            .ends_with("matched no files"));
+        assert!(collect(&["../secret"])
+            .err()
+            .unwrap()
+            .ends_with("must stay inside the project folder"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+//! Smallest images `exe` accepts, shared by the section and resource tests.
+
+fn put(data: &mut [u8], at: usize, bytes: &[u8]) {
+    data[at..at + bytes.len()].copy_from_slice(bytes);
+}
+
+/// An ELF64 header, a section name table and two section headers: the null
+/// section and `.shstrtab`.
+pub fn minimal_elf() -> Vec<u8> {
+    let names = b"\0.shstrtab\0";
+    let mut data = vec![0u8; 80 + 2 * 64];
+    put(&mut data, 0, b"\x7fELF\x02\x01"); // ELFCLASS64, little-endian
+    put(&mut data, 0x28, &80u64.to_le_bytes()); // e_shoff
+    put(&mut data, 0x3a, &64u16.to_le_bytes()); // e_shentsize
+    put(&mut data, 0x3c, &2u16.to_le_bytes()); // e_shnum
+    put(&mut data, 0x3e, &1u16.to_le_bytes()); // e_shstrndx
+    put(&mut data, 64, names);
+
+    let strtab = 80 + 64;
+    put(&mut data, strtab, &1u32.to_le_bytes());
+    put(&mut data, strtab + 4, &3u32.to_le_bytes()); // SHT_STRTAB
+    put(&mut data, strtab + 0x18, &64u64.to_le_bytes());
+    put(&mut data, strtab + 0x20, &(names.len() as u64).to_le_bytes());
+    data
+}
+
+/// A PE32+ image with 0x200 bytes of headers, the optional header at 0x58
+/// and one `.text` section of 0x200 bytes loaded at 0x1000.
+pub fn minimal_pe() -> Vec<u8> {
+    const OPTIONAL: usize = 0x58;
+    let mut data = vec![0u8; 0x400];
+    put(&mut data, 0, b"MZ");
+    put(&mut data, 0x3c, &0x40u32.to_le_bytes());
+    put(&mut data, 0x40, b"PE\0\0");
+    put(&mut data, 0x44, &0x8664u16.to_le_bytes());
+    put(&mut data, 0x46, &1u16.to_le_bytes()); // NumberOfSections
+    put(&mut data, 0x54, &(112u16 + 16 * 8).to_le_bytes()); // SizeOfOptionalHeader
+
+    put(&mut data, OPTIONAL, &0x20bu16.to_le_bytes());
+    put(&mut data, OPTIONAL + 32, &0x1000u32.to_le_bytes()); // SectionAlignment
+    put(&mut data, OPTIONAL + 36, &0x200u32.to_le_bytes()); // FileAlignment
+    put(&mut data, OPTIONAL + 56, &0x2000u32.to_le_bytes()); // SizeOfImage
+    put(&mut data, OPTIONAL + 60, &0x200u32.to_le_bytes()); // SizeOfHeaders
+    put(&mut data, OPTIONAL + 108, &16u32.to_le_bytes()); // NumberOfRvaAndSizes
+
+    let text = OPTIONAL + 112 + 16 * 8;
+    put(&mut data, text, b".text");
+    put(&mut data, text + 8, &0x10u32.to_le_bytes());
+    put(&mut data, text + 12, &0x1000u32.to_le_bytes());
+    put(&mut data, text + 16, &0x200u32.to_le_bytes());
+    put(&mut data, text + 20, &0x200u32.to_le_bytes());
+    data
+}
@@ -0,0 +1,624 @@
+use crate::bytecode::{Limits, Op, Program, Value};
+use crate::caps::{self, BUILTINS};
+use crate::resources::Resource;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Process exit code of a program stopped by one of its `Limits`, distinct
+/// from the -1 of every other error.
+pub const LIMIT_EXIT_CODE: i32 = 3;
+
+/// The wall-clock limit is only checked every this many instructions, and
+/// after every builtin or host call.
+const TIME_CHECK_INTERVAL: u64 = 256;
+
+/// Deepest nesting of ON function calls without a `Depth` limit, so runaway
+/// recursion ends in an error instead of exhausting memory.
+pub const MAX_DEPTH: usize = 10_000;
+
+/// A native function registered by the application embedding bimble. It
+/// receives the call's arguments in order. Host functions are `Send + Sync`
+/// so an `Engine` can be built on one thread and run on another.
+pub type HostFn = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+/// An active ON function call.
+pub struct Frame {
+    pub function: usize,
+    /// Instruction to resume at once the function returns.
+    pub return_pc: usize,
+}
+
+/// Which of the program's `Limits` stopped it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Instructions,
+    Depth,
+    Memory,
+    Time,
+}
+
+impl Limit {
+    pub fn describe(self, limits: &Limits) -> String {
+        match self {
+            Limit::Instructions => format!(
+                "Instruction limit of {} exceeded",
+                limits.instructions.unwrap_or(0)
+            ),
+            Limit::Depth => format!(
+                "Recursion depth limit of {} exceeded",
+                limits.depth.unwrap_or(0)
+            ),
+            Limit::Memory => format!(
+                "Memory limit of {} bytes exceeded",
+                limits.memory.unwrap_or(0)
+            ),
+            Limit::Time => format!(
+                "Time limit of {}ms exceeded",
+                limits.time.unwrap_or_default().as_millis()
+            ),
+        }
+    }
+}
+
+/// One entry of a call stack, innermost first.
+pub struct StackFrame {
+    /// `main` or the ON function name.
+    pub name: String,
+    pub line: Option<u32>,
+}
+
+/// A fatal runtime error with the call stack at the failing instruction.
+pub struct RuntimeError {
+    pub msg: String,
+    pub trace: Vec<StackFrame>,
+    /// main.bb text of every frame's line, filled in by `quote` where the
+    /// source is at hand. Payloads never carry it.
+    pub source: Vec<Option<String>>,
+    /// Set when the error is an exceeded limit.
+    pub limit: Option<Limit>,
+}
+
+impl RuntimeError {
+    pub fn exit_code(&self) -> i32 {
+        match self.limit {
+            Some(_) => LIMIT_EXIT_CODE,
+            None => -1,
+        }
+    }
+
+    /// Adds the text of every frame's line from `source`, the lines of main.bb.
+    pub fn quote(mut self, source: &[String]) -> Self {
+        self.source = self
+            .trace
+            .iter()
+            .map(|frame| {
+                let text = source.get((frame.line? as usize).checked_sub(1)?)?;
+                Some(text.trim().to_string())
+            })
+            .collect();
+        self
+    }
+}
+
+/// Formats like a `check` error, `msg at code (N): line`, followed by one
+/// `in <function> at code (N): line` entry per frame. Programs built without a
+/// line table only name the functions, and lines are only quoted once `quote`
+/// added them.
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)?;
+        if let Some(StackFrame {
+            line: Some(line), ..
+        }) = self.trace.first()
+        {
+            write!(f, " at code ({})", line)?;
+            if let Some(Some(text)) = self.source.first() {
+                write!(f, ": {}", text)?;
+            }
+        }
+        let frames: Vec<_> = self.trace.iter().zip(&self.source).collect();
+        let mut i = 0;
+        while i < frames.len() {
+            let (frame, text) = frames[i];
+            write!(f, "\n    in {}", frame.name)?;
+            if let Some(line) = frame.line {
+                write!(f, " at code ({})", line)?;
+            }
+            if let Some(text) = text {
+                write!(f, ": {}", text)?;
+            }
+            // Deep recursion repeats one frame; print it once with a count
+            let same = frames[i + 1..]
+                .iter()
+                .take_while(|(next, _)| next.name == frame.name && next.line == frame.line)
+                .count();
+            if same > 1 {
+                write!(f, "\n    ... repeated {} more times", same)?;
+                i += same;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+/// An output for `with_output` that keeps what the program printed. Clones
+/// share the buffer, so a clone kept by the caller reads what was written
+/// after the VM took ownership of the other.
+#[derive(Clone, Default)]
+pub struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Captured {
+    /// Returns the output written so far and empties the buffer.
+    pub fn take(&self) -> Vec<u8> {
+        self.0.take()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Running,
+    Halted,
+}
+
+/// Executes a bytecode program one instruction at a time.
+pub struct Vm {
+    pub program: Program,
+    pub resources: Vec<Resource>,
+    /// Current value of every program variable, `None` until first stored.
+    pub vars: Vec<Option<Value>>,
+    pub stack: Vec<Value>,
+    pub frames: Vec<Frame>,
+    pub pc: usize,
+    /// Instructions executed so far.
+    pub executed: u64,
+    /// Bytes held by the stack, variables and call frames.
+    pub memory: u64,
+    /// The limit that stopped the program, if any.
+    pub exceeded: Option<Limit>,
+    started: Option<Instant>,
+    out: Box<dyn Write>,
+    hosts: HashMap<String, HostFn>,
+}
+
+impl Vm {
+    pub fn new(program: Program, resources: Vec<Resource>) -> Self {
+        Self {
+            vars: vec![None; program.vars.len()],
+            program,
+            resources,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            pc: 0,
+            executed: 0,
+            memory: 0,
+            exceeded: None,
+            started: None,
+            out: Box::new(io::stdout()),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Provides the functions called by the program's `Op::Host`
+    /// instructions.
+    pub fn with_hosts(mut self, hosts: HashMap<String, HostFn>) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    /// Sends `echoln` output to `out` instead of stdout.
+    pub fn with_output(mut self, out: Box<dyn Write>) -> Self {
+        self.out = out;
+        self
+    }
+
+    /// Leaves `paused` out of the time limit, for time spent stopped in a
+    /// debugger.
+    pub fn exclude_time(&mut self, paused: Duration) {
+        if let Some(started) = &mut self.started {
+            *started += paused;
+        }
+    }
+
+    /// Runs the program until it halts.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            match self.step() {
+                Ok(Status::Running) => (),
+                Ok(Status::Halted) => return Ok(()),
+                Err(msg) => return Err(self.error(msg)),
+            }
+        }
+    }
+
+    /// Wraps an error returned by `step` with the call stack at the failing
+    /// instruction.
+    pub fn error(&self, msg: String) -> RuntimeError {
+        // The failing instruction is the one before pc
+        let trace = self.backtrace(self.pc.saturating_sub(1));
+        let source = vec![None; trace.len()];
+        RuntimeError {
+            msg,
+            trace,
+            source,
+            limit: self.exceeded,
+        }
+    }
+
+    /// The call stack with the innermost frame at instruction `pc`.
+    pub fn backtrace(&self, pc: usize) -> Vec<StackFrame> {
+        let mut trace = Vec::new();
+        let mut pc = pc;
+        for frame in self.frames.iter().rev() {
+            trace.push(StackFrame {
+                name: self.program.fns[frame.function].name.clone(),
+                line: self.program.line_of(pc),
+            });
+            // The caller is paused on its Call instruction
+            pc = frame.return_pc - 1;
+        }
+        trace.push(StackFrame {
+            name: "main".to_string(),
+            line: self.program.line_of(pc),
+        });
+        trace
+    }
+
+    /// Executes the instruction at `pc`, failing once a limit of the program
+    /// is exceeded.
+    pub fn step(&mut self) -> Result<Status, String> {
+        let op = *self
+            .program
+            .code
+            .get(self.pc)
+            .ok_or_else(|| format!("Jumped outside the program to pc {}", self.pc))?;
+        self.pc += 1;
+
+        let limits = self.program.limits;
+        self.executed += 1;
+        if limits.instructions.is_some_and(|max| self.executed > max) {
+            return Err(self.exceed(Limit::Instructions));
+        }
+        if let Some(max) = limits.time {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if self.executed.is_multiple_of(TIME_CHECK_INTERVAL) && started.elapsed() > max {
+                return Err(self.exceed(Limit::Time));
+            }
+        }
+
+        match op {
+            Op::Const(n) => self.push(self.program.consts[n as usize].clone()),
+            Op::Load(n) => match &self.vars[n as usize] {
+                Some(value) => self.push(value.clone()),
+                None => {
+                    return Err(format!(
+                        "Variable '{}' is used before it is assigned",
+                        self.program.vars[n as usize]
+                    ))
+                }
+            },
+            Op::Store(n) => {
+                let value = self.pop()?;
+                self.memory += size(&value);
+                if let Some(old) = self.vars[n as usize].replace(value) {
+                    self.memory -= size(&old);
+                }
+            }
+            Op::Echo(n) => {
+                let at = self
+                    .stack
+                    .len()
+                    .checked_sub(n as usize)
+                    .ok_or_else(|| "Stack underflow".to_string())?;
+                let values: Vec<Value> = self.stack.drain(at..).collect();
+                self.memory -= values.iter().map(size).sum::<u64>();
+                let line: String = values.iter().map(|v| v.to_string()).collect();
+                writeln!(self.out, "{}", line)
+                    .map_err(|e| format!("Unable to write output: {}", e))?;
+            }
+            Op::Call(n) => {
+                match limits.depth {
+                    Some(max) if self.frames.len() >= max as usize => {
+                        return Err(self.exceed(Limit::Depth));
+                    }
+                    None if self.frames.len() >= MAX_DEPTH => {
+                        return Err(format!("Recursion depth limit of {} exceeded", MAX_DEPTH));
+                    }
+                    _ => (),
+                }
+                self.memory += size_of::<Frame>() as u64;
+                self.frames.push(Frame {
+                    function: n as usize,
+                    return_pc: self.pc,
+                });
+                self.pc = self.program.fns[n as usize].entry as usize;
+            }
+            Op::Resource(n) => {
+                let name = self.program.consts[n as usize].to_string();
+                let resource = self
+                    .resources
+                    .iter()
+                    .find(|r| r.name == name)
+                    .ok_or_else(|| {
+                        format!("Resource '{}' is not embedded in this program", name)
+                    })?;
+                let value = Value::Str(String::from_utf8_lossy(&resource.data).into_owned());
+                self.push(value);
+            }
+            Op::Builtin(n) => {
+                let argc = BUILTINS[n as usize].args;
+                let at = self
+                    .stack
+                    .len()
+                    .checked_sub(argc)
+                    .ok_or_else(|| "Stack underflow".to_string())?;
+                let args: Vec<Value> = self.stack.drain(at..).collect();
+                self.memory -= args.iter().map(size).sum::<u64>();
+                // A spawned process writes to the same terminal
+                self.out
+                    .flush()
+                    .map_err(|e| format!("Unable to write output: {}", e))?;
+                if let Some(value) = caps::call(n as usize, &args, &self.program.capabilities)? {
+                    self.push(value);
+                }
+            }
+            Op::Host(n) => {
+                let call = &self.program.hosts[n as usize];
+                let host =
+                    self.hosts.get(&call.name).cloned().ok_or_else(|| {
+                        format!("Host function '{}' is not registered", call.name)
+                    })?;
+                let returns = call.returns;
+                let at = self
+                    .stack
+                    .len()
+                    .checked_sub(call.args as usize)
+                    .ok_or_else(|| "Stack underflow".to_string())?;
+                let args: Vec<Value> = self.stack.drain(at..).collect();
+                self.memory -= args.iter().map(size).sum::<u64>();
+                let value = host(&args)?;
+                if returns {
+                    self.push(value);
+                }
+            }
+            Op::Ret => match self.frames.pop() {
+                Some(frame) => {
+                    self.memory -= size_of::<Frame>() as u64;
+                    self.pc = frame.return_pc;
+                }
+                None => return Err("Return outside of a function".to_string()),
+            },
+            Op::Halt => {
+                self.out
+                    .flush()
+                    .map_err(|e| format!("Unable to write output: {}", e))?;
+                return Ok(Status::Halted);
+            }
+        }
+        if limits.memory.is_some_and(|max| self.memory > max) {
+            return Err(self.exceed(Limit::Memory));
+        }
+        // Builtins and host functions may block for a long time
+        if matches!(op, Op::Builtin(_) | Op::Host(_))
+            && limits
+                .time
+                .is_some_and(|max| self.started.is_some_and(|s| s.elapsed() > max))
+        {
+            return Err(self.exceed(Limit::Time));
+        }
+        Ok(Status::Running)
+    }
+
+    fn exceed(&mut self, limit: Limit) -> String {
+        self.exceeded = Some(limit);
+        limit.describe(&self.program.limits)
+    }
+
+    fn push(&mut self, value: Value) {
+        self.memory += size(&value);
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Result<Value, String> {
+        let value = self
+            .stack
+            .pop()
+            .ok_or_else(|| "Stack underflow".to_string())?;
+        self.memory -= size(&value);
+        Ok(value)
+    }
+}
+
+/// Bytes `value` occupies, counting string contents.
+pub fn size(value: &Value) -> u64 {
+    let heap = match value {
+        Value::Str(s) => s.len(),
+        Value::Int(_) | Value::Float(_) => 0,
+    };
+    (size_of::<Value>() + heap) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::HostCall;
+    use crate::caps::Capabilities;
+    use crate::check::{check_program, Checker};
+    use crate::codegen::lower;
+
+    fn compile(source: &str) -> Program {
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        let mut checker = Checker::new(&[], &Capabilities::default());
+        let checked = check_program(&mut checker, &lines, &[]).unwrap();
+        lower(&checked, true).ok().unwrap()
+    }
+
+    /// Runs `program`, returning its result and everything it printed.
+    fn run(program: Program) -> (Result<(), RuntimeError>, String) {
+        let output = Captured::default();
+        let result = Vm::new(program, Vec::new())
+            .with_output(Box::new(output.clone()))
+            .run();
+        let printed = String::from_utf8(output.take()).unwrap();
+        (result, printed)
+    }
+
+    #[test]
+    fn nested_calls_print_in_order() {
+        let source = "\
+may name = \"joy\"
+echoln(\"start\")
+outer()
+ON outer(){
+    inner()
+    echoln(\"back in outer\")
+}
+ON inner(){
+    echoln(\"hi \", name)
+}";
+        let (result, printed) = run(compile(source));
+        assert!(result.is_ok());
+        assert_eq!(printed, "start\nhi joy\nback in outer\n");
+    }
+
+    #[test]
+    fn errors_carry_the_call_stack() {
+        let source = "f()\nON f(){\n    echoln(\"x\")\n}";
+        let mut program = compile(source);
+        // Read an unassigned variable instead of printing
+        program.vars.push("x".to_string());
+        let echo = program.fns[0].entry as usize;
+        program.code[echo] = Op::Load(0);
+        let (result, _) = run(program);
+        let e = result.err().unwrap();
+        assert_eq!(e.exit_code(), -1);
+        assert_eq!(
+            e.to_string(),
+            "Variable 'x' is used before it is assigned at code (3)\
+             \n    in f at code (3)\
+             \n    in main at code (1)"
+        );
+        let lines: Vec<String> = source.lines().map(String::from).collect();
+        assert_eq!(
+            e.quote(&lines).to_string(),
+            "Variable 'x' is used before it is assigned at code (3): echoln(\"x\")\
+             \n    in f at code (3): echoln(\"x\")\
+             \n    in main at code (1): f()"
+        );
+    }
+
+    /// Runs `source` under `limits` and returns the limit that stopped it.
+    fn exceeded(source: &str, limits: Limits) -> Option<Limit> {
+        let mut program = compile(source);
+        program.limits = limits;
+        let e = run(program).0.err()?;
+        assert_eq!(e.exit_code(), LIMIT_EXIT_CODE, "{}", e);
+        e.limit
+    }
+
+    const RECURSE: &str = "f()\nON f(){\n    f()\n}";
+
+    #[test]
+    fn every_limit_stops_the_program() {
+        let none = Limits::default();
+        let cases = [
+            (
+                Limits {
+                    instructions: Some(1000),
+                    ..none
+                },
+                Limit::Instructions,
+            ),
+            (
+                Limits {
+                    depth: Some(50),
+                    ..none
+                },
+                Limit::Depth,
+            ),
+            (
+                Limits {
+                    memory: Some(4096),
+                    ..none
+                },
+                Limit::Memory,
+            ),
+            (
+                // An explicit depth lifts the default cap of MAX_DEPTH
+                Limits {
+                    time: Some(Duration::from_millis(1)),
+                    depth: Some(u32::MAX),
+                    ..none
+                },
+                Limit::Time,
+            ),
+        ];
+        for (limits, limit) in cases {
+            assert_eq!(exceeded(RECURSE, limits), Some(limit));
+        }
+
+        // Without a Depth limit recursion still ends, with a plain error
+        let e = run(compile(RECURSE)).0.err().unwrap();
+        assert_eq!((e.limit, e.exit_code()), (None, -1));
+        assert!(
+            e.msg.starts_with("Recursion depth limit of 10000"),
+            "{}",
+            e.msg
+        );
+    }
+
+    #[test]
+    fn blocking_host_calls_count_towards_the_time_limit() {
+        let program = Program {
+            code: vec![Op::Host(0), Op::Halt],
+            hosts: vec![HostCall {
+                name: "wait".to_string(),
+                args: 0,
+                returns: false,
+            }],
+            limits: Limits {
+                time: Some(Duration::from_millis(5)),
+                ..Limits::default()
+            },
+            ..Program::default()
+        };
+        let wait: HostFn = Arc::new(|_| {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(Value::Int(0))
+        });
+        let e = Vm::new(program, Vec::new())
+            .with_hosts(HashMap::from([("wait".to_string(), wait)]))
+            .run()
+            .err()
+            .unwrap();
+        assert_eq!(e.limit, Some(Limit::Time));
+    }
+
+    #[test]
+    fn programs_within_their_limits_finish() {
+        let limits = Limits {
+            instructions: Some(10),
+            depth: Some(1),
+            memory: Some(1024),
+            time: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(exceeded("f()\nON f(){\n    may x = 1\n}", limits), None);
+    }
+}
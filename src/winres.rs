@@ -0,0 +1,493 @@
+use crate::config::CompilerConfig;
+use bimble::exe::resource_section;
+
+const RT_ICON: u16 = 3;
+const RT_GROUP_ICON: u16 = 14;
+const RT_VERSION: u16 = 16;
+
+/// US English, the language every resource is recorded under.
+const LANGUAGE: u16 = 0x0409;
+/// Unicode (UTF-16LE) code page of the string table.
+const CODE_PAGE: u16 = 1200;
+
+/// Resources of one type, as (ID, data) pairs sorted by ID.
+type ResourceType = (u16, Vec<(u16, Vec<u8>)>);
+
+/// The string values written to the VERSIONINFO resource, in order.
+pub fn version_strings(config: &CompilerConfig) -> Vec<(&'static str, String)> {
+    vec![
+        ("CompanyName", config.authors.clone()),
+        ("FileDescription", config.name.clone()),
+        ("FileVersion", config.version.clone()),
+        ("InternalName", config.name.clone()),
+        ("OriginalFilename", config.artifact_name("windows")),
+        ("ProductName", config.name.clone()),
+        ("ProductVersion", config.version.clone()),
+    ]
+}
+
+/// Builds the `.rsrc` section for a Windows artifact: a VERSIONINFO resource
+/// and, when `icon` holds the bytes of an `.ico` file, its images and icon
+/// group. `rva` is the address the section is loaded at.
+pub fn build_resources(
+    strings: &[(&str, String)],
+    version: &str,
+    icon: Option<&[u8]>,
+    rva: u32,
+) -> Result<Vec<u8>, String> {
+    let mut types: Vec<ResourceType> = Vec::new();
+    if let Some(icon) = icon {
+        let (images, group) = parse_ico(icon)?;
+        types.push((
+            RT_ICON,
+            images
+                .into_iter()
+                .enumerate()
+                .map(|(i, image)| ((i + 1) as u16, image))
+                .collect(),
+        ));
+        types.push((RT_GROUP_ICON, vec![(1, group)]));
+    }
+    types.push((RT_VERSION, vec![(1, version_info(strings, version))]));
+    Ok(resource_directory(&types, rva))
+}
+
+/// Splits a `.ico` file into its images and the matching RT_GROUP_ICON entry,
+/// which refers to the images by resource ID 1..n.
+fn parse_ico(ico: &[u8]) -> Result<(Vec<Vec<u8>>, Vec<u8>), String> {
+    let u16_at = |at: usize| {
+        ico.get(at..at + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |at: usize| {
+        ico.get(at..at + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+    if u16_at(0) != Some(0) || u16_at(2) != Some(1) {
+        return Err("Icon is not a .ico file".to_string());
+    }
+    let count = u16_at(4).unwrap_or(0) as usize;
+    if count == 0 {
+        return Err("Icon file contains no images".to_string());
+    }
+
+    let mut images = Vec::new();
+    let mut group = Vec::new();
+    group.extend_from_slice(&0u16.to_le_bytes());
+    group.extend_from_slice(&1u16.to_le_bytes());
+    group.extend_from_slice(&(count as u16).to_le_bytes());
+    for i in 0..count {
+        let entry = 6 + i * 16;
+        let truncated = || "Icon file is truncated".to_string();
+        let size = u32_at(entry + 8).ok_or_else(truncated)? as usize;
+        let offset = u32_at(entry + 12).ok_or_else(truncated)? as usize;
+        let image = offset
+            .checked_add(size)
+            .and_then(|end| ico.get(offset..end))
+            .ok_or_else(truncated)?;
+        images.push(image.to_vec());
+
+        // Width, height, colours, reserved, planes, bit count and size are
+        // copied as-is; the image offset becomes a resource ID
+        group.extend_from_slice(&ico[entry..entry + 12]);
+        group.extend_from_slice(&((i + 1) as u16).to_le_bytes());
+    }
+    Ok((images, group))
+}
+
+/// Packs a dotted version such as `1.2.3` into the two 32-bit halves of
+/// VS_FIXEDFILEINFO. Missing or non-numeric parts count as 0.
+fn version_numbers(version: &str) -> (u32, u32) {
+    let mut parts = [0u32; 4];
+    for (part, text) in parts.iter_mut().zip(version.split('.')) {
+        let digits: String = text.chars().take_while(|c| c.is_ascii_digit()).collect();
+        *part = digits.parse::<u16>().unwrap_or(0) as u32;
+    }
+    (parts[0] << 16 | parts[1], parts[2] << 16 | parts[3])
+}
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain(Some(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect()
+}
+
+fn pad4(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().div_ceil(4) * 4, 0);
+}
+
+/// One node of a version resource: length, value length, type, key, value
+/// and children, each aligned to 32 bits.
+fn version_node(
+    key: &str,
+    value: &[u8],
+    value_len: u16,
+    text: bool,
+    children: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut node = vec![0; 4];
+    node.extend_from_slice(&(text as u16).to_le_bytes());
+    node.extend(utf16(key));
+    pad4(&mut node);
+    node.extend_from_slice(value);
+    for child in children {
+        pad4(&mut node);
+        node.extend_from_slice(child);
+    }
+    let length = node.len() as u16;
+    node[0..2].copy_from_slice(&length.to_le_bytes());
+    node[2..4].copy_from_slice(&value_len.to_le_bytes());
+    node
+}
+
+fn version_info(strings: &[(&str, String)], version: &str) -> Vec<u8> {
+    let (ms, ls) = version_numbers(version);
+    let mut fixed = Vec::new();
+    for field in [
+        0xfeef04bd, // signature
+        0x0001_0000,
+        ms,
+        ls,
+        ms, // product version
+        ls,
+        0x3f, // file flags mask
+        0,
+        0x0004_0004, // VOS_NT_WINDOWS32
+        1,           // VFT_APP
+        0,
+        0,
+        0,
+    ] {
+        fixed.extend_from_slice(&u32::to_le_bytes(field));
+    }
+
+    let entries: Vec<Vec<u8>> = strings
+        .iter()
+        .map(|(key, value)| {
+            let chars = value.encode_utf16().count() as u16 + 1;
+            version_node(key, &utf16(value), chars, true, &[])
+        })
+        .collect();
+    let table_key = format!("{:04X}{:04X}", LANGUAGE, CODE_PAGE);
+    let table = version_node(&table_key, &[], 0, true, &entries);
+    let string_info = version_node("StringFileInfo", &[], 0, true, &[table]);
+
+    let mut translation = LANGUAGE.to_le_bytes().to_vec();
+    translation.extend_from_slice(&CODE_PAGE.to_le_bytes());
+    let var = version_node("Translation", &translation, 4, false, &[]);
+    let var_info = version_node("VarFileInfo", &[], 0, true, &[var]);
+
+    version_node(
+        "VS_VERSION_INFO",
+        &fixed,
+        fixed.len() as u16,
+        false,
+        &[string_info, var_info],
+    )
+}
+
+/// Lays out a three-level resource directory (type, ID, language) followed
+/// by the data entries and the data itself. Directory offsets are relative
+/// to the section; data entries hold addresses, hence `rva`.
+fn resource_directory(types: &[ResourceType], rva: u32) -> Vec<u8> {
+    const DIR: usize = 16;
+    const ENTRY: usize = 8;
+    const DATA_ENTRY: usize = 16;
+    const SUBDIRECTORY: u32 = 0x8000_0000;
+
+    let resources: usize = types.iter().map(|(_, r)| r.len()).sum();
+    let type_dirs = DIR + types.len() * ENTRY;
+    let id_dirs_len: usize = types.iter().map(|(_, r)| DIR + r.len() * ENTRY).sum();
+    let lang_dirs = type_dirs + id_dirs_len;
+    let data_entries = lang_dirs + resources * (DIR + ENTRY);
+    let mut data_at = data_entries + resources * DATA_ENTRY;
+
+    let directory = |count: usize| {
+        let mut dir = vec![0; 12];
+        dir.extend_from_slice(&0u16.to_le_bytes());
+        dir.extend_from_slice(&(count as u16).to_le_bytes());
+        dir
+    };
+    let entry = |id: u16, offset: usize, flag: u32| {
+        let mut e = (id as u32).to_le_bytes().to_vec();
+        e.extend_from_slice(&(offset as u32 | flag).to_le_bytes());
+        e
+    };
+
+    let mut out = directory(types.len());
+    let mut id_dir = type_dirs;
+    for (kind, list) in types {
+        out.extend(entry(*kind, id_dir, SUBDIRECTORY));
+        id_dir += DIR + list.len() * ENTRY;
+    }
+
+    let mut n = 0;
+    for (_, list) in types {
+        out.extend(directory(list.len()));
+        for (id, _) in list {
+            out.extend(entry(*id, lang_dirs + (n * (DIR + ENTRY)), SUBDIRECTORY));
+            n += 1;
+        }
+    }
+
+    for i in 0..resources {
+        out.extend(directory(1));
+        out.extend(entry(LANGUAGE, data_entries + i * DATA_ENTRY, 0));
+    }
+
+    let mut blobs = Vec::new();
+    for (_, list) in types {
+        for (_, data) in list {
+            data_at = data_at.div_ceil(8) * 8;
+            out.extend_from_slice(&(rva + data_at as u32).to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&[0; 8]);
+            blobs.push((data_at, data));
+            data_at += data.len();
+        }
+    }
+    for (at, data) in blobs {
+        out.resize(at, 0);
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// Version information and icons read back from a PE image.
+pub struct WinResources {
+    pub file_version: String,
+    pub strings: Vec<(String, String)>,
+    /// Width and height of every image in the first icon group.
+    pub icons: Vec<(u32, u32)>,
+}
+
+/// Reads the VERSIONINFO and icon group written by `build_resources`.
+/// Returns `None` when the image has no resource table.
+pub fn read_resources(data: &[u8]) -> Result<Option<WinResources>, String> {
+    let Some((rva, table)) = resource_section(data)? else {
+        return Ok(None);
+    };
+    let truncated = || "PE resource table is truncated".to_string();
+    let u16_at = |buf: &[u8], at: usize| -> Result<u16, String> {
+        buf.get(at..at + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    let u32_at = |buf: &[u8], at: usize| -> Result<u32, String> {
+        buf.get(at..at + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(truncated)
+    };
+
+    // Follows type -> first ID -> first language down to the resource data
+    let find = |kind: u16| -> Result<Option<&[u8]>, String> {
+        let mut dir = 0;
+        let mut id = Some(kind);
+        loop {
+            let count = u16_at(table, dir + 12)? as usize + u16_at(table, dir + 14)? as usize;
+            let mut next = None;
+            for i in 0..count {
+                let e = dir + 16 + i * 8;
+                let matches = match id {
+                    Some(id) => u32_at(table, e)? == id as u32,
+                    None => true,
+                };
+                if matches {
+                    next = Some(u32_at(table, e + 4)?);
+                    break;
+                }
+            }
+            let Some(offset) = next else {
+                return Ok(None);
+            };
+            if offset & 0x8000_0000 == 0 {
+                let at = u32_at(table, offset as usize)?
+                    .checked_sub(rva)
+                    .ok_or_else(truncated)? as usize;
+                let size = u32_at(table, offset as usize + 4)? as usize;
+                return table.get(at..at + size).map(Some).ok_or_else(truncated);
+            }
+            dir = (offset & 0x7fff_ffff) as usize;
+            id = None;
+        }
+    };
+
+    let mut resources = WinResources {
+        file_version: String::new(),
+        strings: Vec::new(),
+        icons: Vec::new(),
+    };
+
+    if let Some(version) = find(RT_VERSION)? {
+        // VS_FIXEDFILEINFO starts after the 6-byte header and the key
+        let fixed = (6 + utf16("VS_VERSION_INFO").len()).div_ceil(4) * 4;
+        let (ms, ls) = (u32_at(version, fixed + 8)?, u32_at(version, fixed + 12)?);
+        resources.file_version =
+            format!("{}.{}.{}.{}", ms >> 16, ms & 0xffff, ls >> 16, ls & 0xffff);
+        read_strings(version, 0, &mut resources.strings)?;
+    }
+
+    if let Some(group) = find(RT_GROUP_ICON)? {
+        for i in 0..u16_at(group, 4)? as usize {
+            let entry = 6 + i * 14;
+            let size = |b: u8| if b == 0 { 256 } else { b as u32 };
+            let (w, h) = (
+                *group.get(entry).ok_or_else(truncated)?,
+                *group.get(entry + 1).ok_or_else(truncated)?,
+            );
+            resources.icons.push((size(w), size(h)));
+        }
+    }
+
+    Ok(Some(resources))
+}
+
+/// Walks a version node and collects the key/value pairs of every text node
+/// without children, i.e. the entries of the string tables.
+fn read_strings(buf: &[u8], at: usize, out: &mut Vec<(String, String)>) -> Result<(), String> {
+    let truncated = || "VERSIONINFO resource is truncated".to_string();
+    let word = |at: usize| {
+        buf.get(at..at + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .ok_or_else(truncated)
+    };
+    let text = |mut at: usize| -> Result<(String, usize), String> {
+        let mut units = Vec::new();
+        loop {
+            let unit = word(at)?;
+            at += 2;
+            if unit == 0 {
+                return Ok((String::from_utf16_lossy(&units), at));
+            }
+            units.push(unit);
+        }
+    };
+
+    let end = at + word(at)? as usize;
+    let value_len = word(at + 2)? as usize;
+    let is_text = word(at + 4)? == 1;
+    let (key, after_key) = text(at + 6)?;
+    let value_at = after_key.div_ceil(4) * 4;
+    let value_bytes = if is_text { value_len * 2 } else { value_len };
+    let mut child = (value_at + value_bytes).div_ceil(4) * 4;
+
+    if child >= end {
+        if is_text && value_len > 0 {
+            out.push((key, text(value_at)?.0));
+        }
+        return Ok(());
+    }
+    while child < end {
+        let len = word(child)? as usize;
+        if len == 0 {
+            break;
+        }
+        read_strings(buf, child, out)?;
+        child = (child + len).div_ceil(4) * 4;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_images::minimal_pe;
+    use bimble::exe::add_resource_section;
+
+    /// A `.ico` file holding one image per size, each `size` bytes of data.
+    fn ico(sizes: &[u8]) -> Vec<u8> {
+        let mut data = [0u16, 1, sizes.len() as u16]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let mut offset = 6 + sizes.len() * 16;
+        for &size in sizes {
+            data.extend_from_slice(&[size, size, 0, 0]);
+            data.extend_from_slice(&1u16.to_le_bytes());
+            data.extend_from_slice(&32u16.to_le_bytes());
+            data.extend_from_slice(&(size as u32).to_le_bytes());
+            data.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += size as usize;
+        }
+        for &size in sizes {
+            data.extend(vec![size; size as usize]);
+        }
+        data
+    }
+
+    fn strings() -> Vec<(&'static str, String)> {
+        vec![
+            ("CompanyName", "Ada".to_string()),
+            ("FileDescription", "demo app".to_string()),
+            ("FileVersion", "1.2.3".to_string()),
+        ]
+    }
+
+    fn with_resources(icon: Option<&[u8]>) -> Vec<u8> {
+        add_resource_section(&minimal_pe(), |rva| {
+            build_resources(&strings(), "1.2.3", icon, rva)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn version_info_round_trip() {
+        let resources = read_resources(&with_resources(None)).unwrap().unwrap();
+        assert_eq!(resources.file_version, "1.2.3.0");
+        let expected: Vec<(String, String)> = strings()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+        assert_eq!(resources.strings, expected);
+        assert!(resources.icons.is_empty());
+        assert!(read_resources(&minimal_pe()).unwrap().is_none());
+    }
+
+    #[test]
+    fn icon_ids_run_from_one() {
+        let exe = with_resources(Some(&ico(&[16, 32, 0])));
+        let resources = read_resources(&exe).unwrap().unwrap();
+        assert_eq!(resources.icons, [(16, 16), (32, 32), (256, 256)]);
+
+        // The RT_ICON directory lists IDs 1..n, as the icon group refers to them
+        let (_, table) = resource_section(&exe).unwrap().unwrap();
+        let u32_at = |at: usize| u32::from_le_bytes(table[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(16), RT_ICON as u32);
+        let icons = (u32_at(20) & 0x7fff_ffff) as usize;
+        assert_eq!(
+            u16::from_le_bytes([table[icons + 14], table[icons + 15]]),
+            3
+        );
+        let ids: Vec<u32> = (0..3).map(|i| u32_at(icons + 16 + i * 8)).collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        let (images, group) = parse_ico(&ico(&[16, 32, 0])).unwrap();
+        assert_eq!(images[1], vec![32; 32]);
+        let group_ids: Vec<u16> = (0..3)
+            .map(|i| u16::from_le_bytes([group[6 + i * 14 + 12], group[6 + i * 14 + 13]]))
+            .collect();
+        assert_eq!(group_ids, [1, 2, 3]);
+    }
+
+    #[test]
+    fn bad_icons_are_errors() {
+        let icon = ico(&[16]);
+        assert!(parse_ico(b"not an icon").is_err());
+        assert!(parse_ico(&icon[..icon.len() - 1]).is_err());
+        assert!(parse_ico(&icon[..10]).is_err());
+        assert!(parse_ico(&[0, 0, 1, 0, 0, 0]).is_err());
+
+        let mut huge = icon.clone();
+        huge[6 + 8..6 + 16].copy_from_slice(&[0xff; 8]);
+        assert!(parse_ico(&huge).is_err());
+        assert!(build_resources(&strings(), "1", Some(b"BM bitmap"), 0x2000).is_err());
+    }
+
+    #[test]
+    fn versions_pack_into_fixed_file_info() {
+        assert_eq!(version_numbers("1.2.3"), (0x0001_0002, 0x0003_0000));
+        assert_eq!(version_numbers("2.0.1.9-beta"), (0x0002_0000, 0x0001_0009));
+        assert_eq!(version_numbers("x.70000"), (0, 0));
+    }
+}
@@ -0,0 +1,251 @@
+//! Scripted DAP sessions against `bimble dap`, checking breakpoints, the call
+//! stack, variables, stepping, pausing and launch errors.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+const TST: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tst");
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: u64,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_bimble"))
+            .arg("dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Unable to start bimble dap");
+        Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            seq: 0,
+        }
+    }
+
+    fn send(&mut self, command: &str, arguments: Value) {
+        self.seq += 1;
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+    }
+
+    /// Sends a request and returns its response.
+    fn respond(&mut self, command: &str, arguments: Value) -> Value {
+        self.send(command, arguments);
+        self.until(|m| m["type"] == "response")
+    }
+
+    /// Sends a request that must succeed and returns the response body.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let response = self.respond(command, arguments);
+        assert_eq!(
+            response["success"], true,
+            "{} failed: {}",
+            command, response["message"]
+        );
+        response["body"].clone()
+    }
+
+    /// Reads messages until one matches, returning it.
+    fn until(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let message = self.read();
+            if matches(&message) {
+                return message;
+            }
+        }
+    }
+
+    fn event(&mut self, event: &str) -> Value {
+        self.until(|m| m["type"] == "event" && m["event"] == event)["body"].clone()
+    }
+
+    fn read(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            assert_ne!(
+                self.stdout.read_line(&mut header).unwrap(),
+                0,
+                "bimble dap closed its output"
+            );
+            match header.trim().split_once(':') {
+                Some((_, value)) => length = value.trim().parse().unwrap(),
+                None => break,
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn disconnect(mut self) {
+        self.request("disconnect", json!({}));
+        assert!(self.child.wait().unwrap().success(), "bimble dap failed");
+    }
+}
+
+/// A copy of tst with `code` as its main.bb.
+fn project(name: &str, code: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("bimble-dap-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy(format!("{}/cfg.bcf", TST), dir.join("cfg.bcf")).unwrap();
+    fs::write(dir.join("main.bb"), code).unwrap();
+    dir
+}
+
+fn stack(client: &mut Client) -> Vec<(String, u64)> {
+    let stack = client.request("stackTrace", json!({"threadId": 1}));
+    stack["stackFrames"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| {
+            (
+                f["name"].as_str().unwrap().to_string(),
+                f["line"].as_u64().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn breakpoints_variables_and_stepping() {
+    let mut client = Client::start();
+    client.request("initialize", json!({"adapterID": "bimble"}));
+    client.request("launch", json!({"program": TST}));
+    client.event("initialized");
+
+    // Line 4 is the echoln inside greet
+    let set = client.request(
+        "setBreakpoints",
+        json!({"source": {"path": format!("{}/main.bb", TST)}, "breakpoints": [{"line": 4}]}),
+    );
+    assert_eq!(set["breakpoints"][0]["verified"], true);
+    client.request("configurationDone", json!({}));
+
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(
+        stack(&mut client),
+        [("greet".to_string(), 4), ("main".to_string(), 2)]
+    );
+
+    let scopes = client.request("scopes", json!({"frameId": 0}));
+    let reference = scopes["scopes"][0]["variablesReference"].clone();
+    let vars = client.request("variables", json!({"variablesReference": reference}));
+    let name = vars["variables"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|v| v["name"] == "name")
+        .expect("variable 'name' is missing");
+    assert_eq!(
+        (&name["value"], &name["type"]),
+        (&json!("joy"), &json!("Str"))
+    );
+
+    client.request("next", json!({"threadId": 1}));
+    let output = client.until(|m| m["event"] == "output" || m["event"] == "stopped");
+    assert_eq!(output["body"]["output"], "Nice too meet you joy\n");
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(stack(&mut client)[0].1, 5);
+
+    client.request("continue", json!({"threadId": 1}));
+    assert_eq!(client.event("exited")["exitCode"], 0);
+    client.event("terminated");
+    client.disconnect();
+}
+
+#[test]
+fn breakpoint_ids_are_never_reused() {
+    let mut client = Client::start();
+    client.request("initialize", json!({"adapterID": "bimble"}));
+    client.request("launch", json!({"program": TST}));
+    client.event("initialized");
+
+    let main = json!({"path": format!("{}/main.bb", TST)});
+    let set_line = |client: &mut Client, source: &Value| {
+        let set = client.request(
+            "setBreakpoints",
+            json!({"source": source, "breakpoints": [{"line": 4}]}),
+        );
+        set["breakpoints"][0].clone()
+    };
+    let first = set_line(&mut client, &main)["id"].as_u64().unwrap();
+    let function = client.request(
+        "setFunctionBreakpoints",
+        json!({"breakpoints": [{"name": "greet"}]}),
+    );
+    let function = function["breakpoints"][0]["id"].as_u64().unwrap();
+    client.request("setFunctionBreakpoints", json!({"breakpoints": []}));
+    let second = set_line(&mut client, &main)["id"].as_u64().unwrap();
+    assert!(first < function && function < second);
+
+    // Breakpoints in other files are refused and leave main.bb's alone
+    let other = set_line(&mut client, &json!({"path": "/elsewhere/other.bb"}));
+    assert_eq!(other["verified"], false);
+    assert!(other.get("id").is_none());
+
+    client.request("configurationDone", json!({}));
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["hitBreakpointIds"], json!([second]));
+    client.disconnect();
+}
+
+#[test]
+fn launch_errors_fail_the_request() {
+    let dir = project("launch", "echoln(x)\n");
+    let mut client = Client::start();
+    client.request("initialize", json!({"adapterID": "bimble"}));
+    client.send("launch", json!({"program": dir}));
+
+    let output = client.event("output");
+    assert_eq!(output["category"], "stderr");
+    let msg = "Invalid argument to 'echoln' -> x at code (1): echoln(x)";
+    assert_eq!(output["output"], format!("{}\n", msg));
+    let response = client.until(|m| m["type"] == "response");
+    assert_eq!(
+        (&response["success"], &response["message"]),
+        (&json!(false), &json!(msg))
+    );
+    client.disconnect();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pause_interrupts_continue() {
+    // 2^20 calls, far more than one request's worth of steps
+    let mut code = String::new();
+    for i in 0..20 {
+        code += &format!("ON f{}(){{\n    f{}()\n    f{}()\n}}\n", i, i + 1, i + 1);
+    }
+    code += "ON f20(){\n    may x = 1\n}\nf0()\n";
+    let dir = project("pause", &code);
+    let mut client = Client::start();
+    client.request("initialize", json!({"adapterID": "bimble"}));
+    client.request("launch", json!({"program": dir}));
+    client.request("configurationDone", json!({}));
+
+    client.request("pause", json!({"threadId": 1}));
+    assert_eq!(client.event("stopped")["reason"], "pause");
+    assert!(stack(&mut client).len() > 1, "paused outside the calls");
+    client.disconnect();
+    fs::remove_dir_all(dir).unwrap();
+}